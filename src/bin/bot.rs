@@ -0,0 +1,91 @@
+// A HEADLESS CLIENT FOR TRYING OUT THE LOBBY
+// It doesn't open a window or play any sounds.  Each bot gets in line for a match, joins it, swings
+// its sword around at random for a while, and then leaves.  For example:
+//
+//     cargo run --bin server -- --room duel --match-size 2
+//     cargo run --bin bot -- 4 localhost
+
+use rand::prelude::{thread_rng, Rng};
+use rusty_sword_arena::{
+    game::{PlayerInput, QueueStatus},
+    gfx::Vec2,
+    net::ConnectionToServer,
+};
+use std::env;
+use std::f32::consts::PI;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long each bot plays once it gets a match
+const PLAY_TIME: Duration = Duration::from_secs(30);
+
+fn run_bot(name: String, host: String) {
+    let mut connection = ConnectionToServer::new(&host);
+    if let Err(err) = connection.queue(&name, None) {
+        println!("{}: {}", name, err);
+        return;
+    }
+
+    // Wait for a match
+    loop {
+        match connection.queue_status() {
+            Ok(QueueStatus::Matched { room, .. }) => {
+                println!("{}: Matched in room {}", name, room);
+                break;
+            }
+            Ok(status) => println!("{}: {}", name, status),
+            Err(err) => {
+                println!("{}: {}", name, err);
+                return;
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let my_id = match connection.join(&name) {
+        Ok(id) => id,
+        Err(err) => {
+            println!("{}: {}", name, err);
+            return;
+        }
+    };
+
+    // Wander around and swing at whatever is nearby
+    let mut rng = thread_rng();
+    let mut player_input = PlayerInput::with_id(my_id);
+    let start = Instant::now();
+    while start.elapsed() < PLAY_TIME {
+        // The bot doesn't look at the game states, but they still need to be picked up
+        connection.poll_game_states();
+        player_input.direction = (player_input.direction + rng.gen_range(-0.2, 0.2)) % (2.0 * PI);
+        player_input.move_amount =
+            Vec2::new(player_input.direction.cos(), player_input.direction.sin());
+        player_input.attack = rng.gen_bool(0.05);
+        connection.send_player_input(&player_input);
+        thread::sleep(Duration::from_millis(15));
+    }
+    connection.leave(my_id);
+    println!("{}: Done playing", name);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (count, host) = match (args.next().map(|count| count.parse::<usize>()), args.next()) {
+        (Some(Ok(count)), Some(host)) => (count, host),
+        _ => {
+            println!("Usage: bot NUMBER_OF_BOTS HOST");
+            process::exit(2);
+        }
+    };
+    let bots: Vec<thread::JoinHandle<()>> = (1..=count)
+        .map(|number| {
+            let name = format!("Bot {}", number);
+            let host = host.clone();
+            thread::spawn(move || run_bot(name, host))
+        })
+        .collect();
+    for bot in bots {
+        let _ = bot.join();
+    }
+}
@@ -73,7 +73,7 @@ impl Player {
             // Play sounds
             match player_event {
                 PlayerEvent::AttackMiss => audio.play("miss"),
-                PlayerEvent::Die => audio.play("die"),
+                PlayerEvent::Die { .. } => audio.play("die"),
                 PlayerEvent::Spawn => audio.play("spawn"),
                 PlayerEvent::Join => audio.play("join"),
                 PlayerEvent::TookDamage => audio.play("ow"),
@@ -119,7 +119,13 @@ fn main() {
         std::process::exit(3);
     }
     let my_id = response.unwrap();
-    let game_settings = connection.get_game_settings();
+    let game_settings = match connection.get_game_settings() {
+        Ok(game_settings) => game_settings,
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(3);
+        }
+    };
 
     println!(
         "Client v{} connected to server v{} at {}",
@@ -173,6 +179,11 @@ fn main() {
             }
         }
 
+        // Let the player know if we lost the server and couldn't get back in
+        if let Some(err) = connection.take_connection_error() {
+            println!("{}", err);
+        }
+
         // Update player timers
         for player in players.values_mut() {
             player.update_timer(dt);
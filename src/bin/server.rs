@@ -2,21 +2,62 @@ use bincode::{deserialize, serialize};
 use rand::prelude::{thread_rng, Rng, ThreadRng};
 use rusty_sword_arena::{
     game::{
-        Floatable, GameControlMsg, GameSettings, GameState, HighScores, PlayerEvent, PlayerInput,
-        PlayerState,
+        ArenaMap, ChatMessage, DeathCause, Flag, Floatable, GameControlMsg, GameMode, GameSettings,
+        GameState, HighScores, Hill, KillFeedEntry, Pickup, PickupKind, PlayerEvent, PlayerInput,
+        PlayerInputMsg, PlayerState, Projectile, QueueStatus, RankedScore, RoomInfo, Round,
+        RoundPhase, SafeZone, Spectator, Team, Weapon, WeaponKind, ZonePhase, STARTING_RATING,
     },
-    gfx::{clamp_vec_to_magnitude, distance, new_in_square, Color, Vec2},
+    gfx::{angle_facing, clamp_vec_to_magnitude, distance, Color, Vec2},
     net, timer,
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use zmq;
 
+// The most scores the server will send in one page of the scoreboard
+const MAX_SCOREBOARD_PAGE_SIZE: usize = 100;
+
+// Milliseconds. How often the high scores get saved, in case the server doesn't get to save them
+// on the way out.
+const SCORE_SAVE_DELAY: u64 = 60_000;
+
+// The most team chat messages we'll hold on to for a player who hasn't come to get them
+const MAX_TEAM_CHAT_WAITING: usize = 50;
+
+// How far apart (in RGB) two players' colors need to be to tell them apart
+const MIN_COLOR_DISTANCE: f32 = 0.1;
+
+// Milliseconds. How long players in the matchmaking queue can go without asking how it's going
+// before we forget about them
+const QUEUE_DROP_DELAY: u64 = 5000;
+
+// Milliseconds. Every time players wait this long for a match, the lobby lets their ratings be
+// another `GameSettings.match_rating_spread` apart
+const MATCH_SPREAD_GROWTH_DELAY: u64 = 30_000;
+
+// Milliseconds. How long a match room stays saved for the players matched up to play in it, even
+// if none of them have shown up yet
+const MATCH_JOIN_DELAY: u64 = 30_000;
+
 struct ColorPicker {
     index: usize,
     // Colors to take
     colors: Vec<Color>,
+    // Colors players have right now, and whether they came from us (as opposed to being picked by
+    // the player)
+    taken: Vec<(Color, bool)>,
+    // How many colors we've made up since running out
+    generated: usize,
 }
 
 // Why not just a Vec?  Because I've reimplemented this a half-dozen times...
@@ -358,16 +399,557 @@ impl ColorPicker {
         Self {
             index: 5, // Because I like the color
             colors,
+            taken: Vec::new(),
+            generated: 0,
         }
     }
     fn pop_color(&mut self) -> Color {
-        let color = self.colors.remove(self.index);
-        self.index = (self.index + 1) % self.colors.len();
+        let color = if self.colors.is_empty() {
+            self.generate_color()
+        } else {
+            // Skip over any colors that are too close to one a player picked for themselves
+            self.index %= self.colors.len();
+            let index = (0..self.colors.len())
+                .map(|i| (self.index + i) % self.colors.len())
+                .find(|&i| self.is_distinct(&self.colors[i]))
+                .unwrap_or(self.index);
+            self.index = index + 1;
+            self.colors.remove(index)
+        };
+        self.taken.push((color, true));
         color
     }
+    // Use the color the player asked for, unless it isn't a real color or it's too close to
+    // somebody else's
+    fn pick_color(&mut self, preferred: Option<Color>) -> Color {
+        match preferred {
+            Some(color) if is_valid_color(&color) && self.is_distinct(&color) => {
+                self.taken.push((color, false));
+                color
+            }
+            _ => self.pop_color(),
+        }
+    }
     fn push_color(&mut self, color: Color) {
-        self.colors.push(color)
+        let position = self
+            .taken
+            .iter()
+            .position(|(taken, _)| color_distance(taken, &color) == 0.0);
+        if let Some(position) = position {
+            let (color, ours) = self.taken.remove(position);
+            if ours {
+                self.colors.push(color);
+            }
+        }
+    }
+    fn is_distinct(&self, color: &Color) -> bool {
+        self.taken
+            .iter()
+            .all(|(taken, _)| color_distance(taken, color) >= MIN_COLOR_DISTANCE)
+    }
+    // Once the palette runs out, we make up new colors by stepping around the color wheel by the
+    // golden ratio (which never lands in the same place twice), and use whichever of a bunch of
+    // candidates is farthest from every color already taken.
+    fn generate_color(&mut self) -> Color {
+        let mut best = None;
+        let mut best_distance = -1.0;
+        for _ in 0..64 {
+            self.generated += 1;
+            let step = self.generated as f32 * 0.618_034;
+            // Stay light, like the palette, so the colors show up against the dark background
+            let candidate = hsv_color(
+                step % 1.0,
+                0.3 + (step * 3.0) % 0.4,
+                0.7 + (step * 7.0) % 0.3,
+            );
+            let distance = self
+                .taken
+                .iter()
+                .map(|(taken, _)| color_distance(taken, &candidate))
+                .fold(f32::MAX, f32::min);
+            if distance > best_distance {
+                best = Some(candidate);
+                best_distance = distance;
+            }
+        }
+        best.unwrap()
+    }
+}
+
+// Every part of a color has to be a number from 0.0 to 1.0.  Anything else (like NaN, which is
+// never too close to anything) would get past `is_distinct()`.
+fn is_valid_color(color: &Color) -> bool {
+    [color.r, color.g, color.b]
+        .iter()
+        .all(|part| (0.0..=1.0).contains(part))
+}
+
+fn color_distance(a: &Color, b: &Color) -> f32 {
+    ((a.r - b.r).powi(2) + (a.g - b.g).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+// Hue, saturation and value are all from 0.0 to 1.0
+fn hsv_color(hue: f32, saturation: f32, value: f32) -> Color {
+    let sector = hue * 6.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    Color::new(r + m, g + m, b + m)
+}
+
+// Everything that has been fired and hasn't landed yet
+struct Projectiles {
+    next_id: u64,
+    in_flight: Vec<Projectile>,
+}
+
+impl Projectiles {
+    fn new() -> Self {
+        Self {
+            next_id: 0,
+            in_flight: Vec::new(),
+        }
+    }
+    // Fire the shooter's weapon in the direction they are facing
+    fn fire(&mut self, shooter: &mut PlayerState, game_settings: &GameSettings) {
+        let direction = shooter.direction;
+        // Start at the edge of the shooter so we don't have to worry about hitting ourselves
+        let start = shooter.pos + Vec2::new(direction.cos(), direction.sin()) * shooter.radius;
+        if let Some(mut projectile) =
+            Projectile::new(self.next_id, shooter.id, start, direction, &shooter.weapon)
+        {
+            if shooter.has_buff(PickupKind::DamageBuff) {
+                projectile.damage *= game_settings.damage_buff_multiplier;
+            }
+            shooter.player_events.push(PlayerEvent::Fire {
+                projectile_id: projectile.id,
+            });
+            self.next_id += 1;
+            self.in_flight.push(projectile);
+        }
+    }
+}
+
+// Everything lying around the arena waiting to be picked up
+struct Pickups {
+    next_id: u64,
+    spawn_timer: timer::Timer,
+    lying_around: Vec<Pickup>,
+}
+
+impl Pickups {
+    fn new(game_settings: &GameSettings) -> Self {
+        Self {
+            next_id: 0,
+            spawn_timer: timer::Timer::from_millis(game_settings.pickup_spawn_delay),
+            lying_around: Vec::new(),
+        }
+    }
+    // Every so often, put a random pickup somewhere in the arena.  Pickup points that already have
+    // something on them are skipped.
+    fn update(&mut self, game_settings: &GameSettings, delta: Duration, rng: &mut ThreadRng) {
+        self.spawn_timer.update(delta);
+        if !self.spawn_timer.ready || self.lying_around.len() >= game_settings.max_pickups as usize
+        {
+            return;
+        }
+        self.spawn_timer.reset();
+        let map = &game_settings.map;
+        let pos = if map.pickup_points.is_empty() {
+            map.random_safe_pos(rng)
+        } else {
+            let free_points: Vec<&Vec2> = map
+                .pickup_points
+                .iter()
+                .filter(|point| !self.lying_around.iter().any(|x| x.pos == **point))
+                .collect();
+            if free_points.is_empty() {
+                return;
+            }
+            *free_points[rng.gen_range(0, free_points.len())]
+        };
+        let kinds = PickupKind::all();
+        let kind = kinds[rng.gen_range(0, kinds.len())];
+        self.lying_around.push(Pickup::new(self.next_id, kind, pos));
+        self.next_id += 1;
+    }
+}
+
+// Living players pick up whatever they walk over
+fn collect_pickups(
+    pickups: &mut Pickups,
+    player_states: &mut HashMap<u8, PlayerState>,
+    game_settings: &GameSettings,
+) {
+    for player_state in player_states.values_mut() {
+        if player_state.dead {
+            continue;
+        }
+        let (collected, left): (Vec<Pickup>, Vec<Pickup>) = pickups
+            .lying_around
+            .drain(..)
+            .partition(|pickup| pickup.touches(player_state));
+        pickups.lying_around = left;
+        for pickup in collected {
+            match pickup.kind {
+                PickupKind::HealthPotion => player_state.heal(game_settings.potion_heal),
+                PickupKind::Weapon(kind) => player_state.equip(Weapon::from_kind(kind)),
+                kind => player_state.add_buff(kind, game_settings.buff_duration),
+            }
+            player_state
+                .player_events
+                .push(PlayerEvent::Pickup { kind: pickup.kind });
+            println!("{} picked up a {:?}", player_state.name, pickup.kind);
+        }
+    }
+}
+
+// The hills to hold and flags to capture, in game modes that have them
+struct Objectives {
+    hills: Vec<Hill>,
+    flags: Vec<Flag>,
+}
+
+impl Objectives {
+    fn new(game_settings: &GameSettings) -> Self {
+        let map = &game_settings.map;
+        let hills = match game_settings.game_mode {
+            GameMode::KingOfTheHill { .. } => map
+                .hills
+                .iter()
+                .map(|region| Hill::new(region.clone(), game_settings))
+                .collect(),
+            _ => Vec::new(),
+        };
+        // Each team's flag lives at the flag base with the same index as the team
+        let flags = match game_settings.game_mode {
+            GameMode::CaptureTheFlag { .. } => map
+                .flag_bases
+                .iter()
+                .take(game_settings.teams as usize)
+                .enumerate()
+                .map(|(team, home)| Flag::new(team as u8, *home, game_settings))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self { hills, flags }
+    }
+    // Send all the flags home and clear the hills, for a fresh round
+    fn reset(&mut self, player_states: &mut HashMap<u8, PlayerState>) {
+        for player_state in player_states.values_mut() {
+            player_state.flag = None;
+        }
+        for flag in self.flags.iter_mut() {
+            flag.go_home();
+        }
+        for hill in self.hills.iter_mut() {
+            hill.holder = None;
+            hill.point_timer.reset();
+        }
+    }
+}
+
+// Everything that keeps score: the all-time high scores, the current round, and the teams
+struct Scoreboard {
+    game_mode: GameMode,
+    high_scores: HighScores,
+    // The key we gave each identity.  Identities are secret, so they never leave the server --
+    // everyone else only ever sees the key.
+    keys: HashMap<String, u64>,
+    round: Round,
+    teams: Vec<Team>,
+    kills: KillTracker,
+}
+
+impl Scoreboard {
+    // Teams get their colors from the palette, so nobody else can wear them
+    fn new(game_settings: &GameSettings, color_picker: &mut ColorPicker) -> Self {
+        Self {
+            game_mode: game_settings.game_mode,
+            high_scores: HighScores::new(),
+            keys: HashMap::new(),
+            round: Round::new(game_settings),
+            teams: (0..game_settings.teams)
+                .map(|id| Team::new(id, color_picker.pop_color()))
+                .collect(),
+            kills: KillTracker::new(),
+        }
+    }
+    // The key for this identity (see `identity_of()`), handing out a new random one if it's the
+    // first time we've seen it
+    fn key_for(&mut self, identity: &str, rng: &mut ThreadRng) -> u64 {
+        if let Some(&key) = self.keys.get(identity) {
+            return key;
+        }
+        let mut key;
+        loop {
+            key = rng.gen::<u64>();
+            if key != 0 && !self.keys.values().any(|&k| k == key) {
+                break;
+            }
+        }
+        self.keys.insert(identity.to_string(), key);
+        key
+    }
+    // The rating of whoever has this identity, or the starting rating if we've never seen them
+    fn rating_of(&self, identity: &str) -> f32 {
+        match self.keys.get(identity) {
+            Some(&key) => self.high_scores.rating(key),
+            None => STARTING_RATING,
+        }
+    }
+    fn add_player(&mut self, player_state: &PlayerState) {
+        self.high_scores
+            .add_player(player_state.key, &player_state.name);
+        self.round
+            .scores
+            .add_player(player_state.key, &player_state.name);
+        self.round.scores.copy_ratings(&self.high_scores);
+    }
+    // The team with the fewest players on it.  If there's a tie, the team with the lowest total
+    // rating, so the teams stay evenly matched.
+    fn smallest_team(&self, player_states: &HashMap<u8, PlayerState>) -> Option<&Team> {
+        let size_and_rating = |team: &Team| {
+            let members = player_states
+                .values()
+                .filter(|player_state| player_state.team == Some(team.id));
+            let size = members.clone().count();
+            let rating: f32 = members
+                .map(|player_state| self.high_scores.rating(player_state.key))
+                .sum();
+            (size, rating)
+        };
+        self.teams.iter().min_by(|a, b| {
+            let (a_size, a_rating) = size_and_rating(a);
+            let (b_size, b_rating) = size_and_rating(b);
+            a_size.cmp(&b_size).then(
+                a_rating
+                    .partial_cmp(&b_rating)
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+        })
+    }
+    // The killer's rating goes up and the victim's goes down
+    fn rate_kill(&mut self, killer: &PlayerState, victim_key: u64, k_factor: f32) {
+        self.high_scores.rate_kill(killer.key, victim_key, k_factor);
+        self.round.scores.copy_ratings(&self.high_scores);
+    }
+    // Kills (and deaths) only count towards the round (and the teams) while the round is on, and
+    // only in game modes without objectives
+    fn score(&mut self, player_state: &PlayerState) {
+        self.high_scores.score(player_state.key);
+        if !self.game_mode.has_objectives() {
+            self.score_round(player_state, 1);
+        }
+    }
+    fn penalize(&mut self, player_state: &PlayerState) {
+        self.high_scores.penalize(player_state.key);
+        if !self.game_mode.has_objectives() {
+            self.score_round(player_state, -1);
+        }
+    }
+    // Holding a hill or capturing a flag
+    fn score_objective(&mut self, player_state: &PlayerState) {
+        self.high_scores.score(player_state.key);
+        self.score_round(player_state, 1);
+    }
+    fn score_round(&mut self, player_state: &PlayerState, points: i32) {
+        if self.round.phase != RoundPhase::Active {
+            return;
+        }
+        if points > 0 {
+            self.round.score(player_state.key);
+        } else {
+            self.round.penalize(player_state.key);
+        }
+        if let Some(team) = self.team_mut(player_state.team) {
+            team.points += points;
+        }
+    }
+    // Somebody got hurt.  Remember what did it (for kill credit), and keep the stats.
+    fn record_damage(
+        &mut self,
+        victim: &PlayerState,
+        attacker_key: Option<u64>,
+        cause: DeathCause,
+        damage: f32,
+        game_settings: &GameSettings,
+    ) {
+        self.high_scores.stats_mut(victim.key).damage_taken += damage;
+        if let Some(attacker_key) = attacker_key {
+            self.high_scores.stats_mut(attacker_key).damage_dealt += damage;
+        }
+        self.kills.record_damage(victim.id, cause, game_settings);
+    }
+    // Count up everyone's hits and misses from this frame's events
+    fn record_attacks(&mut self, player_states: &HashMap<u8, PlayerState>) {
+        for player_state in player_states.values() {
+            let mut hits = 0;
+            let mut misses = 0;
+            for player_event in player_state.player_events.iter() {
+                match player_event {
+                    PlayerEvent::AttackHit { .. } => hits += 1,
+                    PlayerEvent::AttackMiss => misses += 1,
+                    _ => {}
+                }
+            }
+            if hits + misses > 0 {
+                let stats = self.high_scores.stats_mut(player_state.key);
+                stats.hits += hits;
+                stats.misses += misses;
+            }
+        }
+    }
+    // Add up how long the player has been alive, before dying or leaving starts it over
+    fn record_time_alive(&mut self, player_state: &PlayerState) {
+        self.high_scores.stats_mut(player_state.key).time_alive += player_state.time_alive;
+    }
+    fn team_mut(&mut self, id: Option<u8>) -> Option<&mut Team> {
+        let id = id?;
+        self.teams.iter_mut().find(|team| team.id == id)
+    }
+}
+
+// Keeps track of who hurt whom recently, so that kills (and assists) go to the right players, and
+// keeps the kill feed
+struct KillTracker {
+    // Each player's recent damage, oldest first.  Each timer counts down the assist window.
+    recent_damage: HashMap<u8, Vec<(DeathCause, timer::Timer)>>,
+    feed: Vec<KillFeedEntry>,
+}
+
+impl KillTracker {
+    fn new() -> Self {
+        Self {
+            recent_damage: HashMap::new(),
+            feed: Vec::new(),
+        }
+    }
+    // Forget old damage, and old kills
+    fn update(&mut self, delta: Duration) {
+        for damage in self.recent_damage.values_mut() {
+            for (_, timer) in damage.iter_mut() {
+                timer.update(delta);
+            }
+            damage.retain(|(_, timer)| !timer.ready);
+        }
+        self.recent_damage.retain(|_, damage| !damage.is_empty());
+        for entry in self.feed.iter_mut() {
+            entry.timer.update(delta);
+        }
+        self.feed.retain(|entry| !entry.timer.ready);
+    }
+    // Getting hurt the same way again counts as the most recent damage
+    fn record_damage(&mut self, victim: u8, cause: DeathCause, game_settings: &GameSettings) {
+        let damage = self.recent_damage.entry(victim).or_default();
+        damage.retain(|(x, _)| *x != cause);
+        damage.push((
+            cause,
+            timer::Timer::from_millis(game_settings.assist_window),
+        ));
+    }
+    // Whatever hurt the player most recently
+    fn last_cause(&self, victim: u8) -> Option<DeathCause> {
+        self.recent_damage
+            .get(&victim)
+            .and_then(|damage| damage.last())
+            .map(|(cause, _)| cause.clone())
+    }
+    // Everyone other than the killer who hurt the victim recently.  Forgets the victim's damage.
+    fn take_assists(&mut self, victim: u8, cause: &DeathCause) -> Vec<u8> {
+        let mut assists = Vec::<u8>::new();
+        for (damage_cause, _) in self.recent_damage.remove(&victim).unwrap_or_default() {
+            if let DeathCause::Player { killer, .. } = damage_cause {
+                let is_killer =
+                    matches!(cause, DeathCause::Player { killer: x, .. } if *x == killer);
+                if !is_killer && killer != victim && !assists.contains(&killer) {
+                    assists.push(killer);
+                }
+            }
+        }
+        assists
+    }
+}
+
+// Kill a player, give credit (or blame) for it, and put it in the kill feed.  Killing a teammate
+// costs you a point, and so does getting eaten by a grue.
+fn kill_player(
+    id: u8,
+    cause: DeathCause,
+    player_states: &mut HashMap<u8, PlayerState>,
+    game_settings: &GameSettings,
+    scoreboard: &mut Scoreboard,
+) {
+    let assists = scoreboard.kills.take_assists(id, &cause);
+    let (victim_name, victim_key, victim_team) = match player_states.get_mut(&id) {
+        Some(victim) => {
+            let msg = match &cause {
+                DeathCause::Player { killer, .. } => {
+                    format!("Player {} was slain by player {}.", id, killer)
+                }
+                DeathCause::Grue => format!(
+                    "Player {} was eaten by a grue.  Should have stayed in the light!",
+                    id
+                ),
+                DeathCause::Hazard => {
+                    format!("Player {} stood in the wrong place for too long.", id)
+                }
+                DeathCause::Zone => format!("Player {} was lost to the dark.", id),
+            };
+            scoreboard.record_time_alive(victim);
+            victim.die(&msg, cause.clone());
+            if cause == DeathCause::Grue {
+                scoreboard.penalize(victim);
+            }
+            let stats = scoreboard.high_scores.stats_mut(victim.key);
+            stats.deaths += 1;
+            stats.streak = 0;
+            if !matches!(cause, DeathCause::Player { .. }) {
+                stats.suicides += 1;
+            }
+            (victim.name.clone(), victim.key, victim.team)
+        }
+        None => return,
+    };
+    let mut killer_name = None;
+    if let DeathCause::Player { killer, .. } = cause {
+        // The killer may have left since the blow landed
+        if let Some(killer) = player_states.get_mut(&killer) {
+            if killer.team.is_some() && killer.team == victim_team {
+                scoreboard.penalize(killer);
+            } else {
+                scoreboard.score(killer);
+                scoreboard.rate_kill(killer, victim_key, game_settings.rating_k_factor);
+                let stats = scoreboard.high_scores.stats_mut(killer.key);
+                stats.kills += 1;
+                stats.streak += 1;
+                stats.longest_streak = stats.longest_streak.max(stats.streak);
+            }
+            killer.player_events.push(PlayerEvent::Kill { id });
+            killer_name = Some(killer.name.clone());
+        }
     }
+    for assist in assists.iter() {
+        if let Some(assister) = player_states.get_mut(assist) {
+            assister.player_events.push(PlayerEvent::Assist { id });
+        }
+    }
+    scoreboard.kills.feed.push(KillFeedEntry {
+        victim: id,
+        victim_name,
+        cause,
+        killer_name,
+        assists,
+        timer: timer::Timer::from_millis(game_settings.kill_feed_duration),
+    });
 }
 
 // Returns whether or not the player was actually there to be removed
@@ -375,33 +957,181 @@ fn remove_player(
     id: u8,
     player_states: &mut HashMap<u8, PlayerState>,
     color_picker: &mut ColorPicker,
-    forced: bool,
+    scoreboard: &mut Scoreboard,
+    sessions: &mut Sessions,
+    chat: &mut Chat,
 ) -> bool {
-    let mut msg = format!(
-        "Player {} {}",
-        id,
-        if forced { "kicked for idling" } else { "left" }
-    );
     if let Some(player_state) = player_states.remove(&id) {
-        msg.push_str(&format!(
-            ", name: {}, color: {:?}",
-            player_state.name, player_state.color
-        ));
-        color_picker.push_color(player_state.color);
-        println!("{}", msg);
+        scoreboard.record_time_alive(&player_state);
+        sessions.end(id);
+        retire_player(&player_state, color_picker, chat, "left");
         return true;
     }
     false
 }
 
+// The player is gone for good
+fn retire_player(
+    player_state: &PlayerState,
+    color_picker: &mut ColorPicker,
+    chat: &mut Chat,
+    reason: &str,
+) {
+    println!(
+        "Player {} {}, name: {}, color: {:?}",
+        player_state.id, reason, player_state.name, player_state.color
+    );
+    // Team colors stay with the team
+    if player_state.team.is_none() {
+        color_picker.push_color(player_state.color);
+    }
+    // Whoever gets this id next starts with a clean slate
+    chat.forget(player_state.id);
+}
+
+// Players whose connection drops get a little while to reconnect and pick up where they left off.
+// Each player gets a resume token when they join, which only they know (it isn't in the
+// `PlayerState` everyone sees), so nobody else can take over their player.
+struct Sessions {
+    // The resume token of each player who is here, by id
+    tokens: HashMap<u8, u64>,
+    // Players whose connection dropped, by resume token, and how long until we give up on them
+    disconnected: HashMap<u64, (PlayerState, timer::Timer)>,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Self {
+            tokens: HashMap::new(),
+            disconnected: HashMap::new(),
+        }
+    }
+    // A new player joined, so they need a new resume token
+    fn start(&mut self, id: u8, rng: &mut ThreadRng) -> u64 {
+        let token = rng.gen::<u64>();
+        self.tokens.insert(id, token);
+        token
+    }
+    // The player left on purpose, so there's nothing to resume
+    fn end(&mut self, id: u8) {
+        self.tokens.remove(&id);
+    }
+    // Hang on to a player whose connection dropped, in case they come back
+    fn disconnect(&mut self, mut player_state: PlayerState, game_settings: &GameSettings) {
+        let token = match self.tokens.remove(&player_state.id) {
+            Some(token) => token,
+            None => return,
+        };
+        println!(
+            "Player {} disconnected, name: {}.  Holding their place for a while.",
+            player_state.id, player_state.name
+        );
+        // Any flag they were carrying got dropped where they were
+        player_state.flag = None;
+        let timer = timer::Timer::from_millis(game_settings.resume_grace_period);
+        self.disconnected.insert(token, (player_state, timer));
+    }
+    // The player is back!
+    fn resume(&mut self, token: u64) -> Option<PlayerState> {
+        let (player_state, _) = self.disconnected.remove(&token)?;
+        self.tokens.insert(player_state.id, token);
+        Some(player_state)
+    }
+    // Players who didn't make it back in time
+    fn update(&mut self, delta: Duration) -> Vec<PlayerState> {
+        let mut expired = Vec::new();
+        for (_, timer) in self.disconnected.values_mut() {
+            timer.update(delta);
+        }
+        let tokens: Vec<u64> = self
+            .disconnected
+            .iter()
+            .filter(|(_, (_, timer))| timer.ready)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            if let Some((player_state, _)) = self.disconnected.remove(&token) {
+                expired.push(player_state);
+            }
+        }
+        expired
+    }
+    // Whether a disconnected player is still holding on to this id
+    fn holds_id(&self, id: u8) -> bool {
+        self.disconnected
+            .values()
+            .any(|(player_state, _)| player_state.id == id)
+    }
+    // Disconnected players who are joining again from scratch instead of resuming (maybe their
+    // client crashed and lost its token) don't get their old place back.  Only the same identity
+    // counts -- a name alone isn't enough to take over someone's place.
+    fn take_rejoining(&mut self, key: u64) -> Vec<PlayerState> {
+        let tokens: Vec<u64> = self
+            .disconnected
+            .iter()
+            .filter(|(_, (player_state, _))| player_state.key == key)
+            .map(|(token, _)| *token)
+            .collect();
+        tokens
+            .iter()
+            .filter_map(|token| self.disconnected.remove(token))
+            .map(|(player_state, _)| player_state)
+            .collect()
+    }
+}
+
+// Which room a game control request is for.  Players and spectators who are already in a room
+// find it by their id or token.
+fn find_room(msg: &GameControlMsg, arenas: &[Arena]) -> Result<usize, String> {
+    let found = match msg {
+        GameControlMsg::Join { room, .. } => {
+            return match arenas.get(*room as usize) {
+                Some(_) => Ok(*room as usize),
+                None => Err(format!("Join Failed: There is no room {}.", room)),
+            };
+        }
+        GameControlMsg::Spectate { room, .. } => {
+            return match arenas.get(*room as usize) {
+                Some(_) => Ok(*room as usize),
+                None => Err(format!("Spectate Failed: There is no room {}.", room)),
+            };
+        }
+        GameControlMsg::Fetch { room }
+        | GameControlMsg::FetchScoreboard { room }
+        | GameControlMsg::FetchScoreboardPage { room, .. }
+        | GameControlMsg::FetchRank { room, .. } => {
+            return match arenas.get(*room as usize) {
+                Some(_) => Ok(*room as usize),
+                None => Err(format!("Fetch Failed: There is no room {}.", room)),
+            };
+        }
+        GameControlMsg::Leave { id, .. }
+        | GameControlMsg::Chat { id, .. }
+        | GameControlMsg::FetchTeamChat { id, .. } => {
+            arenas.iter().position(|arena| arena.has_player(*id))
+        }
+        GameControlMsg::Resume { token }
+        | GameControlMsg::Follow { token, .. }
+        | GameControlMsg::StopSpectating { token } => {
+            arenas.iter().position(|arena| arena.has_token(*token))
+        }
+        GameControlMsg::ListRooms
+        | GameControlMsg::Queue { .. }
+        | GameControlMsg::FetchQueueStatus { .. }
+        | GameControlMsg::LeaveQueue { .. } => None,
+    };
+    // Anything we can't place goes to the first room, which turns down ids and tokens it doesn't
+    // know
+    Ok(found.unwrap_or(0))
+}
+
 #[allow(clippy::never_loop)]
 fn process_game_control_requests(
     game_control_server_socket: &mut zmq::Socket,
-    game_settings: &mut GameSettings,
-    player_states: &mut HashMap<u8, PlayerState>,
+    arenas: &mut [Arena],
+    lobby: &mut Lobby,
     rng: &mut ThreadRng,
-    color_picker: &mut ColorPicker,
-    high_scores: &mut HighScores,
+    name_rules: &NameRules,
 ) {
     'gamecontrol: loop {
         match game_control_server_socket.recv_multipart(0) {
@@ -409,12 +1139,168 @@ fn process_game_control_requests(
             Ok(multipart_message) => {
                 let return_identity = &multipart_message[0];
                 let msg: GameControlMsg = deserialize(&multipart_message[2][..]).unwrap();
+                // Listing the rooms and the matchmaking queue aren't for one room in particular
+                let reply = match &msg {
+                    GameControlMsg::ListRooms => {
+                        let rooms: Vec<RoomInfo> = arenas.iter().map(Arena::info).collect();
+                        Some(serialize(&rooms).unwrap())
+                    }
+                    GameControlMsg::Queue {
+                        name,
+                        identity,
+                        room,
+                    } => {
+                        let result =
+                            lobby.add(name, identity.clone(), *room, arenas, name_rules, rng);
+                        if let Err(err) = &result {
+                            println!("{}", err);
+                        }
+                        Some(serialize(&result).unwrap())
+                    }
+                    GameControlMsg::FetchQueueStatus { ticket } => {
+                        Some(serialize(&lobby.status(*ticket)).unwrap())
+                    }
+                    GameControlMsg::LeaveQueue { ticket } => {
+                        Some(serialize(&lobby.remove(*ticket)).unwrap())
+                    }
+                    // A room that doesn't exist has nothing on its scoreboard
+                    GameControlMsg::FetchScoreboard { room } if *room as usize >= arenas.len() => {
+                        Some(serialize(&HighScores::new()).unwrap())
+                    }
+                    GameControlMsg::FetchScoreboardPage {
+                        room,
+                        page,
+                        page_size,
+                    } if *room as usize >= arenas.len() => {
+                        let scoreboard_page = HighScores::new()
+                            .page(*page, (*page_size).min(MAX_SCOREBOARD_PAGE_SIZE));
+                        Some(serialize(&scoreboard_page).unwrap())
+                    }
+                    GameControlMsg::FetchRank { room, .. } if *room as usize >= arenas.len() => {
+                        Some(serialize(&None::<RankedScore>).unwrap())
+                    }
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    game_control_server_socket
+                        .send_multipart(&[&return_identity[..], &[], &reply], 0)
+                        .unwrap();
+                    continue;
+                }
+                let room = match find_room(&msg, arenas) {
+                    Ok(room) => room,
+                    Err(err) => {
+                        println!("{}", err);
+                        // Joining, spectating and fetching the settings all answer with a
+                        // `Result<_, String>`, and an `Err` looks the same no matter what the `Ok`
+                        // would have been
+                        let result: Result<(), String> = Err(err);
+                        game_control_server_socket
+                            .send_multipart(
+                                &[&return_identity[..], &[], &serialize(&result).unwrap()],
+                                0,
+                            )
+                            .unwrap();
+                        continue;
+                    }
+                };
+                // Player ids are unique across all the rooms, so the id in each player's input is
+                // enough to tell which room it's for
+                let ids_in_use: Vec<u8> = arenas.iter().flat_map(Arena::ids).collect();
+                let Arena {
+                    game_settings,
+                    player_states,
+                    color_picker,
+                    scoreboard,
+                    sessions,
+                    spectators,
+                    chat,
+                    reservation,
+                    zone,
+                    ..
+                } = &mut arenas[room];
                 match msg {
-                    GameControlMsg::Join { name } => {
-                        let result: Result<u8, String>;
+                    GameControlMsg::ListRooms
+                    | GameControlMsg::Queue { .. }
+                    | GameControlMsg::FetchQueueStatus { .. }
+                    | GameControlMsg::LeaveQueue { .. } => unreachable!(),
+                    GameControlMsg::Join {
+                        name,
+                        weapon,
+                        identity,
+                        color,
+                        skin,
+                        title,
+                        join_ticket,
+                        ..
+                    } => {
+                        let identity = identity_of(identity.as_deref(), &name);
+                        let known_key = scoreboard.keys.get(&identity).cloned();
+                        // Places held for this identity go away if the join works out, so they
+                        // don't count against it
+                        let rejoining = sessions
+                            .disconnected
+                            .values()
+                            .filter(|(player_state, _)| Some(player_state.key) == known_key)
+                            .count();
+                        let result: Result<(u8, u64), String>;
                         loop {
-                            // Is the game full?
-                            if player_states.len() >= game_settings.max_players as usize {
+                            // Is the name allowed (and not already taken)?
+                            let name = match name_rules.check(&name, player_states, game_settings) {
+                                Ok(name) => name,
+                                Err(err) => {
+                                    let err = format!("Join Failed: {}", err);
+                                    println!("{}", err);
+                                    result = Err(err);
+                                    break;
+                                }
+                            };
+                            // Is the name being held for someone else who might reconnect?
+                            if sessions.disconnected.values().any(|(player_state, _)| {
+                                canonical_name(&player_state.name) == canonical_name(&name)
+                                    && Some(player_state.key) != known_key
+                            }) {
+                                let err = format!(
+                                    "Join Failed: Name \"{}\" is being held for someone who left.",
+                                    name
+                                );
+                                println!("{}", err);
+                                result = Err(err);
+                                break;
+                            }
+                            // Match rooms are only for the players the lobby matched up
+                            if game_settings.match_size > 0 {
+                                let invited = match (reservation.as_ref(), join_ticket) {
+                                    (Some(reservation), Some(join_ticket)) => {
+                                        reservation.tickets.contains(&join_ticket)
+                                    }
+                                    _ => false,
+                                };
+                                if !invited {
+                                    let err = format!(
+                                        "Join Failed: Room {} is for matches.  Queue up instead!",
+                                        room
+                                    );
+                                    println!("{}", err);
+                                    result = Err(err);
+                                    break;
+                                }
+                            }
+                            // Is the whole server full?
+                            if ids_in_use.len() >= u8::MAX as usize {
+                                let err = format!(
+                                    "Join Failed: No room for player {} - the server is full!",
+                                    name
+                                );
+                                println!("{}", err);
+                                result = Err(err);
+                                break;
+                            }
+                            // Is the game full?  (Players who might reconnect still have a spot.)
+                            if playing_count(player_states) + sessions.disconnected.len()
+                                - rejoining
+                                >= game_settings.max_players as usize
+                            {
                                 let err = format!(
                                     "Join Failed: No room for player {} - {} players is the max!",
                                     name, game_settings.max_players
@@ -423,41 +1309,95 @@ fn process_game_control_requests(
                                 result = Err(err);
                                 break;
                             }
-                            // Is the name already taken?
+                            // Is someone already playing as this identity?
                             if player_states
                                 .values()
-                                .map(|player_state| &player_state.name)
-                                .any(|x| x == &name)
+                                .any(|player_state| Some(player_state.key) == known_key)
                             {
                                 let err =
-                                    format!("Join Failed: Name \"{}\" is already taken.", name);
+                                    format!("Join Failed: Someone is already playing as {}.", name);
+                                println!("{}", err);
+                                result = Err(err);
+                                break;
+                            }
+                            // Is the skin one that exists?
+                            let skin = skin.unwrap_or(0);
+                            if skin >= game_settings.skins {
+                                let err = format!(
+                                    "Join Failed: There is no skin {} (skins go from 0 to {}).",
+                                    skin,
+                                    game_settings.skins.saturating_sub(1)
+                                );
                                 println!("{}", err);
                                 result = Err(err);
                                 break;
                             }
+                            // Is the title something we're willing to show everyone?
+                            let title = match check_title(title, &game_settings) {
+                                Ok(title) => title,
+                                Err(err) => {
+                                    let err = format!("Join Failed: {}", err);
+                                    println!("{}", err);
+                                    result = Err(err);
+                                    break;
+                                }
+                            };
+                            let key = scoreboard.key_for(&identity, rng);
+                            for player_state in sessions.take_rejoining(key) {
+                                retire_player(
+                                    &player_state,
+                                    color_picker,
+                                    chat,
+                                    "rejoined from scratch",
+                                );
+                            }
+                            // Join tickets are only good once
+                            if let (Some(reservation), Some(join_ticket)) =
+                                (reservation.as_mut(), join_ticket)
+                            {
+                                reservation.tickets.retain(|&ticket| ticket != join_ticket);
+                            }
                             // Find a random, unused, non-zero id
                             let mut id;
                             loop {
                                 id = rng.gen::<u8>();
-                                if (id != 0) && !player_states.contains_key(&id) {
+                                if (id != 0) && !ids_in_use.contains(&id) {
                                     break;
                                 }
                             }
-                            // Assign player a color
-                            let color = color_picker.pop_color();
+                            // Put the player on the smallest team and give them the team's color,
+                            // or give them their own color (hopefully the one they asked for) if
+                            // there aren't teams
+                            let team = scoreboard.smallest_team(player_states);
+                            let color = match team {
+                                Some(team) => team.color,
+                                None => color_picker.pick_color(color),
+                            };
+                            let team_id = team.map(|team| team.id);
                             // Create the new player state
-                            let player_state = PlayerState::new(
+                            let mut player_state = PlayerState::new(
                                 &game_settings,
                                 id,
                                 name.clone(),
                                 color,
-                                new_in_square(0.6, rng),
+                                game_settings.map.random_spawn(rng, zone.as_ref()),
                                 0.05,
+                                Weapon::from_kind(weapon.unwrap_or(WeaponKind::RustySword)),
                             );
-                            high_scores.add_player(&player_state.name);
+                            player_state.key = key;
+                            player_state.skin = skin;
+                            player_state.title = title;
+                            player_state.team = team_id;
+                            scoreboard.add_player(&player_state);
+                            println!(
+                                "Joined: {} (id {}) wielding a {}",
+                                name, id, player_state.weapon.description
+                            );
+                            if let Some(team_id) = team_id {
+                                println!("{} is on team {}", name, team_id);
+                            }
                             player_states.insert(id, player_state);
-                            println!("Joined: {} (id {})", name, id,);
-                            result = Ok(id);
+                            result = Ok((id, sessions.start(id, rng)));
                             break;
                         }
                         game_control_server_socket
@@ -467,8 +1407,17 @@ fn process_game_control_requests(
                             )
                             .unwrap();
                     }
-                    GameControlMsg::Leave { id } => {
-                        let succeeded = remove_player(id, player_states, color_picker, false);
+                    GameControlMsg::Leave { id, token } => {
+                        // Only the player themselves can make them leave
+                        let succeeded = sessions.tokens.get(&id) == Some(&token)
+                            && remove_player(
+                                id,
+                                player_states,
+                                color_picker,
+                                scoreboard,
+                                sessions,
+                                chat,
+                            );
                         if succeeded {
                             println!("Player {} left voluntarily.", id);
                         }
@@ -479,74 +1428,1446 @@ fn process_game_control_requests(
                             )
                             .unwrap();
                     }
-                    GameControlMsg::Fetch => {
+                    GameControlMsg::Resume { token } => {
+                        let result: Result<u8, String> = match sessions.resume(token) {
+                            Some(mut player_state) => {
+                                let id = player_state.id;
+                                player_state.drop_timer.reset();
+                                player_state.afk_timer.reset();
+                                player_state.player_events.push(PlayerEvent::Join);
+                                scoreboard.add_player(&player_state);
+                                println!("Player {} resumed, name: {}", id, player_state.name);
+                                player_states.insert(id, player_state);
+                                Ok(id)
+                            }
+                            // If the connection came back before we noticed it was gone, there's
+                            // nothing to do
+                            None => match sessions.tokens.iter().find(|(_, &t)| t == token) {
+                                Some((&id, _)) => Ok(id),
+                                None => Err("Resume Failed: It's been too long, or you left. \
+                                             Join again instead."
+                                    .to_string()),
+                            },
+                        };
                         game_control_server_socket
                             .send_multipart(
-                                &[
-                                    &return_identity[..],
-                                    &[],
-                                    &serialize(&game_settings).unwrap(),
-                                ],
+                                &[&return_identity[..], &[], &serialize(&result).unwrap()],
                                 0,
                             )
                             .unwrap();
-                        println!("A player fetches new settings.");
                     }
-                }
-            }
-        }
-    }
-}
-
-fn coalesce_player_input(
-    player_input_server_socket: &mut zmq::Socket,
-    player_states: &mut HashMap<u8, PlayerState>,
-    player_inputs: &mut HashMap<u8, PlayerInput>,
-) {
-    while let Ok(bytes) = player_input_server_socket.recv_bytes(0) {
-        let player_input: PlayerInput = deserialize(&bytes[..]).unwrap();
-        if let Some(player_state) = player_states.get_mut(&player_input.id) {
-            player_state.drop_timer.reset();
-        }
-        player_inputs
-            .entry(player_input.id)
-            .or_insert_with(|| player_input.clone())
-            .coalesce(player_input);
-    }
-}
-
-fn update_state(
+                    GameControlMsg::Spectate { name, follow, .. } => {
+                        let result: Result<u64, String> =
+                            match name_rules.check(&name, player_states, game_settings) {
+                                Err(err) => Err(format!("Spectate Failed: {}", err)),
+                                Ok(_)
+                                    if spectators.watching.len()
+                                        >= game_settings.max_spectators as usize =>
+                                {
+                                    Err(format!(
+                                        "Spectate Failed: {} spectators is the max!",
+                                        game_settings.max_spectators
+                                    ))
+                                }
+                                Ok(name) => {
+                                    println!("{} is spectating", name);
+                                    let following =
+                                        follow.filter(|id| player_states.contains_key(id));
+                                    let spectator = Spectator { name, following };
+                                    Ok(spectators.add(spectator, game_settings, rng))
+                                }
+                            };
+                        if let Err(err) = &result {
+                            println!("{}", err);
+                        }
+                        game_control_server_socket
+                            .send_multipart(
+                                &[&return_identity[..], &[], &serialize(&result).unwrap()],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::Follow { token, id } => {
+                        // You can only follow players who are here
+                        let player_exists = match id {
+                            Some(id) => player_states.contains_key(&id),
+                            None => true,
+                        };
+                        let succeeded = match spectators.watching.get_mut(&token) {
+                            Some((spectator, _)) if player_exists => {
+                                spectator.following = id;
+                                true
+                            }
+                            _ => false,
+                        };
+                        game_control_server_socket
+                            .send_multipart(
+                                &[&return_identity[..], &[], &serialize(&succeeded).unwrap()],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::StopSpectating { token } => {
+                        let succeeded = match spectators.watching.remove(&token) {
+                            Some((spectator, _)) => {
+                                println!("{} stopped spectating", spectator.name);
+                                true
+                            }
+                            None => false,
+                        };
+                        game_control_server_socket
+                            .send_multipart(
+                                &[&return_identity[..], &[], &serialize(&succeeded).unwrap()],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::Chat {
+                        id,
+                        token,
+                        text,
+                        team_only,
+                    } => {
+                        let result = match player_states.get(&id) {
+                            Some(player_state) if sessions.tokens.get(&id) == Some(&token) => chat
+                                .send(player_state, &text, team_only, player_states, game_settings),
+                            _ => Err("You need to join before you can chat.".to_string()),
+                        };
+                        game_control_server_socket
+                            .send_multipart(
+                                &[&return_identity[..], &[], &serialize(&result).unwrap()],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    // Team chat never goes out where anyone could listen in, so each player has
+                    // to come get theirs
+                    GameControlMsg::FetchTeamChat { id, token } => {
+                        let result = if sessions.tokens.get(&id) == Some(&token) {
+                            Ok(chat.take_team_messages(id))
+                        } else {
+                            Err("You need to join before you can chat.".to_string())
+                        };
+                        game_control_server_socket
+                            .send_multipart(
+                                &[&return_identity[..], &[], &serialize(&result).unwrap()],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::FetchScoreboard { .. } => {
+                        game_control_server_socket
+                            .send_multipart(
+                                &[
+                                    &return_identity[..],
+                                    &[],
+                                    &serialize(&scoreboard.high_scores).unwrap(),
+                                ],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::FetchScoreboardPage {
+                        page, page_size, ..
+                    } => {
+                        let scoreboard_page = scoreboard
+                            .high_scores
+                            .page(page, page_size.min(MAX_SCOREBOARD_PAGE_SIZE));
+                        game_control_server_socket
+                            .send_multipart(
+                                &[
+                                    &return_identity[..],
+                                    &[],
+                                    &serialize(&scoreboard_page).unwrap(),
+                                ],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::FetchRank { key, .. } => {
+                        let ranked_score = scoreboard.high_scores.ranked_score(key);
+                        game_control_server_socket
+                            .send_multipart(
+                                &[
+                                    &return_identity[..],
+                                    &[],
+                                    &serialize(&ranked_score).unwrap(),
+                                ],
+                                0,
+                            )
+                            .unwrap();
+                    }
+                    GameControlMsg::Fetch { .. } => {
+                        game_control_server_socket
+                            .send_multipart(
+                                &[
+                                    &return_identity[..],
+                                    &[],
+                                    &serialize(&Ok::<_, String>(&*game_settings)).unwrap(),
+                                ],
+                                0,
+                            )
+                            .unwrap();
+                        println!("A player fetches new settings.");
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn coalesce_player_input(player_input_server_socket: &mut zmq::Socket, arenas: &mut [Arena]) {
+    while let Ok(bytes) = player_input_server_socket.recv_bytes(0) {
+        let (player_input, token) = match deserialize(&bytes[..]).unwrap() {
+            PlayerInputMsg::Input { input, token } => (input, token),
+            // Heartbeats keep players connected, as long as they really came from the player
+            PlayerInputMsg::Heartbeat { id, token } => {
+                for arena in arenas.iter_mut() {
+                    if arena.sessions.tokens.get(&id) == Some(&token) {
+                        if let Some(player_state) = arena.player_states.get_mut(&id) {
+                            player_state.drop_timer.reset();
+                        }
+                    }
+                }
+                continue;
+            }
+            PlayerInputMsg::SpectatorHeartbeat { token } => {
+                for arena in arenas.iter_mut() {
+                    arena.spectators.heartbeat(token);
+                }
+                continue;
+            }
+        };
+        // Player ids are unique across all the rooms, and only the player knows their token
+        let arena = match arenas.iter_mut().find(|arena| {
+            arena.player_states.contains_key(&player_input.id)
+                && arena.sessions.tokens.get(&player_input.id) == Some(&token)
+        }) {
+            Some(arena) => arena,
+            None => continue,
+        };
+        // Players sitting out gave up their place, so they can only come back if there's room
+        let has_room = playing_count(&arena.player_states) + arena.sessions.disconnected.len()
+            < arena.game_settings.max_players as usize;
+        let player_inputs = &mut arena.player_inputs;
+        if let Some(player_state) = arena.player_states.get_mut(&player_input.id) {
+            if is_active(&player_input, player_inputs.get(&player_input.id)) {
+                player_state.afk_timer.reset();
+                if player_state.afk && has_room {
+                    player_state.afk = false;
+                    player_state.player_events.push(PlayerEvent::BackFromAfk);
+                    println!("Player {} is back", player_state.id);
+                }
+            }
+        }
+        player_inputs
+            .entry(player_input.id)
+            .or_insert_with(|| player_input.clone())
+            .coalesce(player_input);
+    }
+}
+
+// How many players are actually in the game.  Players sitting out for being AFK don't count.
+fn playing_count(player_states: &HashMap<u8, PlayerState>) -> usize {
+    player_states
+        .values()
+        .filter(|player_state| !player_state.afk)
+        .count()
+}
+
+// Whether the player is actually doing something, as opposed to their client sending the same
+// input over and over
+fn is_active(player_input: &PlayerInput, previous: Option<&PlayerInput>) -> bool {
+    let turned = match previous {
+        Some(previous) => (player_input.direction - previous.direction).abs() > 0.01,
+        None => true,
+    };
+    turned
+        || player_input.attack
+        || player_input.block
+        || player_input.dodge
+        || player_input.move_amount.magnitude() > 0.0
+}
+
+// Players who haven't done anything in a while either sit out until they come back, or get kicked
+fn update_afk(
+    player_states: &mut HashMap<u8, PlayerState>,
+    game_settings: &GameSettings,
+    sessions: &mut Sessions,
+    color_picker: &mut ColorPicker,
+    scoreboard: &mut Scoreboard,
+    chat: &mut Chat,
+) {
+    if game_settings.afk_delay == 0 {
+        return;
+    }
+    let afk: Vec<u8> = player_states
+        .values()
+        .filter(|player_state| !player_state.afk && player_state.afk_timer.ready)
+        .map(|player_state| player_state.id)
+        .collect();
+    for id in afk {
+        if game_settings.afk_spectate {
+            if let Some(player_state) = player_states.get_mut(&id) {
+                // Leave the arena without it counting as a death
+                if !player_state.dead {
+                    scoreboard.record_time_alive(player_state);
+                    player_state.time_alive = Duration::from_secs(0);
+                    player_state.dead = true;
+                    player_state.velocity = Vec2::zeros();
+                    player_state.respawn_timer.reset();
+                }
+                player_state.afk = true;
+                player_state.player_events.push(PlayerEvent::Afk);
+                println!("Player {} is AFK and sitting out", id);
+            }
+        } else if let Some(player_state) = player_states.remove(&id) {
+            scoreboard.record_time_alive(&player_state);
+            sessions.end(id);
+            retire_player(&player_state, color_picker, chat, "kicked for being AFK");
+        }
+    }
+}
+
+// How a defender fared against a hit
+enum Defense {
+    Dodged,
+    Parried,
+    Blocked,
+    Unblocked,
+}
+
+// See whether the defender dodges, parries or blocks a hit coming from `from`.  Blocking costs the
+// defender some stamina.
+fn defend(defender: &mut PlayerState, from: &Vec2, game_settings: &GameSettings) -> Defense {
+    if defender.is_dodging() {
+        return Defense::Dodged;
+    }
+    if !defender.blocking || !defender.is_facing(from, game_settings.block_arc) {
+        return Defense::Unblocked;
+    }
+    if !defender.parry_timer.ready {
+        return Defense::Parried;
+    }
+    defender.stamina = (defender.stamina - game_settings.block_cost).max(0.0);
+    Defense::Blocked
+}
+
+// Push apart any living players who overlap, and stop them from moving into each other
+fn resolve_collisions(player_states: &mut HashMap<u8, PlayerState>) {
+    let bodies: Vec<(u8, Vec2, f32)> = player_states
+        .values()
+        .filter(|player_state| !player_state.dead)
+        .map(|player_state| (player_state.id, player_state.pos, player_state.radius))
+        .collect();
+    let mut pushes = HashMap::<u8, Vec2>::new();
+    for (i, (id_a, pos_a, radius_a)) in bodies.iter().enumerate() {
+        for (id_b, pos_b, radius_b) in bodies.iter().skip(i + 1) {
+            let gap = distance(pos_a, pos_b);
+            let overlap = radius_a + radius_b - gap;
+            if overlap <= 0.0 {
+                continue;
+            }
+            // Which way to push b away from a.  If they're exactly on top of each other, any
+            // direction will do.
+            let normal = if gap > 0.0 {
+                (pos_b - pos_a).normalize()
+            } else {
+                Vec2::new(1.0, 0.0)
+            };
+            // Each player gets pushed back half of the way
+            *pushes.entry(*id_a).or_insert_with(Vec2::zeros) -= normal * (overlap * 0.5);
+            *pushes.entry(*id_b).or_insert_with(Vec2::zeros) += normal * (overlap * 0.5);
+        }
+    }
+    for (id, push) in pushes {
+        if push.magnitude() <= 0.0 {
+            continue;
+        }
+        if let Some(player_state) = player_states.get_mut(&id) {
+            push_player(player_state, push);
+        }
+    }
+}
+
+// Keep living players from walking into obstacles
+fn resolve_obstacles(player_states: &mut HashMap<u8, PlayerState>, map: &ArenaMap) {
+    for player_state in player_states.values_mut() {
+        if player_state.dead {
+            continue;
+        }
+        for obstacle in &map.obstacles {
+            if let Some(push) = obstacle.push_out(&player_state.pos, player_state.radius) {
+                push_player(player_state, push);
+            }
+        }
+    }
+}
+
+// Move a player who bumped into something, and stop whatever part of their movement is heading
+// into it
+fn push_player(player_state: &mut PlayerState, push: Vec2) {
+    player_state.pos += push;
+    let away = push.normalize();
+    let into = player_state.velocity.dot(&away);
+    if into < 0.0 {
+        player_state.velocity -= away * into;
+    }
+    let into = player_state.knockback.dot(&away);
+    if into < 0.0 {
+        player_state.knockback -= away * into;
+    }
+}
+
+// If the round is over, returns `Some` with the name of the winner (if anybody won)
+fn round_result(
+    scoreboard: &Scoreboard,
+    player_states: &HashMap<u8, PlayerState>,
+) -> Option<Option<String>> {
+    let round = &scoreboard.round;
+    let leader = round.scores.scores.first();
+    match scoreboard.game_mode {
+        GameMode::FreeForAll => None,
+        GameMode::TimedDeathmatch { .. } => {
+            if round.phase_over() {
+                Some(leader.map(|score| score.name().to_string()))
+            } else {
+                None
+            }
+        }
+        GameMode::FirstTo { points } | GameMode::KingOfTheHill { points } => leader
+            .filter(|score| score.points() >= points)
+            .map(|score| Some(score.name().to_string())),
+        GameMode::CaptureTheFlag { captures } => scoreboard
+            .teams
+            .iter()
+            .find(|team| team.points >= captures)
+            .map(|team| Some(format!("Team {}", team.id))),
+        GameMode::LastPlayerStanding => {
+            // Players sitting out for being AFK aren't in the running
+            let survivors: Vec<&PlayerState> = player_states
+                .values()
+                .filter(|player_state| !player_state.dead && !player_state.afk)
+                .collect();
+            if playing_count(player_states) >= 2 && survivors.len() <= 1 {
+                Some(
+                    survivors
+                        .first()
+                        .map(|player_state| player_state.name.clone()),
+                )
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Start a fresh round: new round scores, and everybody spawns (except players sitting out for
+// being AFK -- they spawn once they're back, if the round lets them)
+fn start_round(
+    scoreboard: &mut Scoreboard,
+    objectives: &mut Objectives,
+    zone: &mut Option<SafeZone>,
+    game_settings: &GameSettings,
+    player_states: &mut HashMap<u8, PlayerState>,
+    rng: &mut ThreadRng,
+) {
+    objectives.reset(player_states);
+    *zone = SafeZone::new(game_settings, rng);
+    for team in scoreboard.teams.iter_mut() {
+        team.points = 0;
+    }
+    let round = &mut scoreboard.round;
+    println!("Round {} begins!", round.number);
+    round.phase = RoundPhase::Active;
+    round.phase_timer = match game_settings.game_mode {
+        GameMode::TimedDeathmatch { duration } => Some(timer::Timer::from_millis(duration)),
+        _ => None,
+    };
+    round.scores = HighScores::new();
+    for (id, player_state) in player_states.iter_mut() {
+        round
+            .scores
+            .add_player(player_state.key, &player_state.name);
+        if player_state.afk {
+            continue;
+        }
+        let pos = game_settings.map.random_spawn(rng, zone.as_ref());
+        player_state.respawn(pos, &format!("Player {} spawns for the round", id));
+    }
+    round.scores.copy_ratings(&scoreboard.high_scores);
+}
+
+// Move the round through its lifecycle, according to the game mode
+fn update_round(
+    scoreboard: &mut Scoreboard,
+    objectives: &mut Objectives,
+    zone: &mut Option<SafeZone>,
+    game_settings: &GameSettings,
+    player_states: &mut HashMap<u8, PlayerState>,
+    delta: Duration,
+    rng: &mut ThreadRng,
+) {
+    let round = &mut scoreboard.round;
+    round.update(delta);
+    match round.phase {
+        RoundPhase::Warmup => {
+            if round.phase_over() {
+                start_round(
+                    scoreboard,
+                    objectives,
+                    zone,
+                    game_settings,
+                    player_states,
+                    rng,
+                );
+            }
+        }
+        RoundPhase::Active => {
+            if let Some(winner) = round_result(scoreboard, player_states) {
+                let round = &mut scoreboard.round;
+                println!(
+                    "Round {} is over! Winner: {}\n{}",
+                    round.number,
+                    winner.as_ref().map_or("nobody", |name| name.as_str()),
+                    round.scores
+                );
+                round.winner = winner;
+                round.phase = RoundPhase::Intermission;
+                round.phase_timer =
+                    Some(timer::Timer::from_millis(game_settings.intermission_delay));
+            }
+        }
+        RoundPhase::Intermission => {
+            if round.phase_over() {
+                round.number += 1;
+                round.phase = RoundPhase::Warmup;
+                round.phase_timer = Some(timer::Timer::from_millis(game_settings.warmup_delay));
+            }
+        }
+    }
+}
+
+// How to set up one of the rooms
+struct RoomConfig {
+    name: String,
+    game_settings: GameSettings,
+    score_file: ScoreFile,
+}
+
+impl RoomConfig {
+    fn new(name: &str, score_path: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            game_settings: GameSettings::new(),
+            score_file: ScoreFile {
+                path: score_path.to_string(),
+                new_season: false,
+            },
+        }
+    }
+}
+
+// Apply command-line options to the rooms' game settings.  There's always at least one room.
+// Options apply to the room most recently started with `--room`, or to the first room if they come
+// before any `--room`.
+fn parse_args(name_rules: &mut NameRules, lobby: &mut Lobby) -> Vec<RoomConfig> {
+    let usage = "Usage: server [--name-blocklist FILE] [--reserved-names FILE] \
+                 [--name-characters CHARACTERS] [--queue-timeout SECONDS] [ROOM OPTIONS] \
+                 [--room NAME [ROOM OPTIONS]]...\n\
+                 ROOM OPTIONS are: [--map FILE] [--teams NUMBER] [--friendly-fire] \
+                 [--shrinking-zone] [--mode MODE] [--scores FILE] [--new-season] \
+                 [--match-size NUMBER]\n\
+                 MODE is one of: free-for-all, timed, first-to, last-standing, king-of-the-hill, \
+                 capture-the-flag";
+    let mut rooms = vec![RoomConfig::new("main", "high_scores.json")];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--room" {
+            // Room names end up in file names, so keep them simple
+            let name = match args.next() {
+                Some(name)
+                    if !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                        && rooms.iter().all(|room| room.name != name) =>
+                {
+                    name
+                }
+                _ => {
+                    println!(
+                        "Rooms need unique names made of letters, numbers, - and _\n{}",
+                        usage
+                    );
+                    process::exit(2);
+                }
+            };
+            if rooms.len() > u8::MAX as usize {
+                println!("That's too many rooms\n{}", usage);
+                process::exit(2);
+            }
+            rooms.push(RoomConfig::new(
+                &name,
+                &format!("high_scores-{}.json", name),
+            ));
+            continue;
+        }
+        let room = rooms.last_mut().unwrap();
+        let game_settings = &mut room.game_settings;
+        let score_file = &mut room.score_file;
+        // Flags that don't take a value
+        if arg == "--new-season" {
+            score_file.new_season = true;
+            continue;
+        }
+        if arg == "--friendly-fire" {
+            game_settings.friendly_fire = true;
+            continue;
+        }
+        if arg == "--shrinking-zone" {
+            game_settings.zone_phases = default_zone_phases();
+            continue;
+        }
+        match (arg.as_str(), args.next()) {
+            ("--map", Some(path)) => game_settings.map = load_map(&path),
+            ("--scores", Some(path)) => score_file.path = path,
+            ("--name-blocklist", Some(path)) => name_rules.load_blocklist(&path),
+            ("--reserved-names", Some(path)) => name_rules.load_reserved(&path),
+            ("--name-characters", Some(characters)) => {
+                // Letters, numbers and spaces are always allowed, and anything that isn't
+                // punctuation has no business in a name
+                if !characters.chars().all(|c| c.is_ascii_punctuation()) {
+                    println!("Invalid name characters {}\n{}", characters, usage);
+                    process::exit(2);
+                }
+                name_rules.punctuation = characters;
+            }
+            ("--queue-timeout", Some(seconds)) => {
+                lobby.timeout = match seconds.parse() {
+                    Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                    _ => {
+                        println!("Invalid queue timeout {}\n{}", seconds, usage);
+                        process::exit(2);
+                    }
+                }
+            }
+            ("--match-size", Some(size)) => {
+                game_settings.match_size = match size.parse() {
+                    // A match needs at least two players, and they all have to fit
+                    Ok(size) if size >= 2 && size <= game_settings.max_players => size,
+                    _ => {
+                        println!("Invalid match size {}\n{}", size, usage);
+                        process::exit(2);
+                    }
+                }
+            }
+            ("--mode", Some(mode)) => {
+                game_settings.game_mode = match mode.as_str() {
+                    "free-for-all" => GameMode::FreeForAll,
+                    "timed" => GameMode::TimedDeathmatch { duration: 300_000 },
+                    "first-to" => GameMode::FirstTo { points: 10 },
+                    "last-standing" => GameMode::LastPlayerStanding,
+                    "king-of-the-hill" => GameMode::KingOfTheHill { points: 60 },
+                    "capture-the-flag" => GameMode::CaptureTheFlag { captures: 3 },
+                    _ => {
+                        println!("Unknown game mode {}\n{}", mode, usage);
+                        process::exit(2);
+                    }
+                }
+            }
+            ("--teams", Some(teams)) => {
+                game_settings.teams = match teams.parse() {
+                    // One team would just be everybody, and every team needs room for two players
+                    Ok(teams) if teams != 1 && teams <= game_settings.max_players / 2 => teams,
+                    _ => {
+                        println!("Invalid number of teams {}\n{}", teams, usage);
+                        process::exit(2);
+                    }
+                }
+            }
+            _ => {
+                println!("{}", usage);
+                process::exit(2);
+            }
+        }
+    }
+    // Make sure each map has what its game mode needs
+    for room in rooms.iter_mut() {
+        let game_settings = &mut room.game_settings;
+        match game_settings.game_mode {
+            GameMode::KingOfTheHill { .. } if game_settings.map.hills.is_empty() => {
+                println!("Map {} doesn't have any hills", game_settings.map.name);
+                process::exit(2);
+            }
+            GameMode::CaptureTheFlag { .. } => {
+                // Capture the flag doesn't make any sense without teams
+                if game_settings.teams == 0 {
+                    game_settings.teams = 2;
+                }
+                if game_settings.map.flag_bases.len() < game_settings.teams as usize {
+                    println!(
+                        "Map {} only has flag bases for {} teams",
+                        game_settings.map.name,
+                        game_settings.map.flag_bases.len()
+                    );
+                    process::exit(2);
+                }
+            }
+            _ => {}
+        }
+    }
+    rooms
+}
+
+// The darkness closes in over about three minutes, getting hungrier as it goes
+fn default_zone_phases() -> Vec<ZonePhase> {
+    vec![
+        ZonePhase {
+            wait: 30000,
+            shrink: 30000,
+            radius: 0.7,
+            damage_per_second: 5.0,
+        },
+        ZonePhase {
+            wait: 20000,
+            shrink: 20000,
+            radius: 0.45,
+            damage_per_second: 10.0,
+        },
+        ZonePhase {
+            wait: 20000,
+            shrink: 15000,
+            radius: 0.25,
+            damage_per_second: 20.0,
+        },
+        ZonePhase {
+            wait: 15000,
+            shrink: 15000,
+            radius: 0.1,
+            damage_per_second: 40.0,
+        },
+    ]
+}
+
+// Load a map from a JSON file.  There's no point in running the server with a map other than the
+// one that was asked for, so we just quit if it doesn't work.
+fn load_map(path: &str) -> ArenaMap {
+    let result = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+        // A map that parses can still be one nobody could play on
+        .and_then(|map: ArenaMap| map.check().map(|()| map));
+    match result {
+        Ok(map) => map,
+        Err(err) => {
+            println!("Failed to load map {}: {}", path, err);
+            process::exit(2);
+        }
+    }
+}
+
+// Chat messages waiting to go out, team chat waiting for each player to come get it, and when each
+// player sent their latest messages (so nobody can flood the chat)
+struct Chat {
+    outbox: Vec<ChatMessage>,
+    team_inboxes: HashMap<u8, Vec<ChatMessage>>,
+    recent: HashMap<u8, Vec<Instant>>,
+}
+
+impl Chat {
+    fn new() -> Self {
+        Self {
+            outbox: Vec::new(),
+            team_inboxes: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+    fn take_team_messages(&mut self, id: u8) -> Vec<ChatMessage> {
+        self.team_inboxes.remove(&id).unwrap_or_default()
+    }
+    fn forget(&mut self, id: u8) {
+        self.team_inboxes.remove(&id);
+        self.recent.remove(&id);
+    }
+    // Tidy up the message, and make sure the player is allowed to send it right now
+    fn send(
+        &mut self,
+        player_state: &PlayerState,
+        text: &str,
+        team_only: bool,
+        player_states: &HashMap<u8, PlayerState>,
+        game_settings: &GameSettings,
+    ) -> Result<(), String> {
+        let text = text
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if text.is_empty() {
+            return Err("Chat messages can't be empty.".to_string());
+        }
+        if text.chars().count() > game_settings.max_chat_length {
+            return Err(format!(
+                "Chat messages can't be longer than {} characters.",
+                game_settings.max_chat_length
+            ));
+        }
+        if team_only && player_state.team.is_none() {
+            return Err("You aren't on a team.".to_string());
+        }
+        let window = Duration::from_millis(game_settings.chat_rate_window);
+        let recent = self.recent.entry(player_state.id).or_default();
+        recent.retain(|sent| sent.elapsed() < window);
+        if recent.len() >= game_settings.chat_rate_limit {
+            return Err(format!(
+                "Slow down!  You can only send {} chat messages every {} seconds.",
+                game_settings.chat_rate_limit,
+                window.as_secs()
+            ));
+        }
+        recent.push(Instant::now());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        let message = ChatMessage {
+            id: player_state.id,
+            name: player_state.name.clone(),
+            team: if team_only { player_state.team } else { None },
+            text,
+            timestamp,
+        };
+        println!("CHAT: {}", message);
+        if message.team.is_none() {
+            self.outbox.push(message);
+            return Ok(());
+        }
+        // Teammates who never come get their team chat only hang on to the latest messages
+        for teammate in player_states.values() {
+            if teammate.team == message.team {
+                let inbox = self.team_inboxes.entry(teammate.id).or_default();
+                inbox.push(message.clone());
+                if inbox.len() > MAX_TEAM_CHAT_WAITING {
+                    inbox.remove(0);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Everyone watching instead of playing, by token.  Spectators have to send heartbeats too, or we
+// forget about them.
+struct Spectators {
+    watching: HashMap<u64, (Spectator, timer::Timer)>,
+}
+
+impl Spectators {
+    fn new() -> Self {
+        Self {
+            watching: HashMap::new(),
+        }
+    }
+    fn add(
+        &mut self,
+        spectator: Spectator,
+        game_settings: &GameSettings,
+        rng: &mut ThreadRng,
+    ) -> u64 {
+        let token = rng.gen::<u64>();
+        let timer = timer::Timer::from_millis(game_settings.drop_delay);
+        self.watching.insert(token, (spectator, timer));
+        token
+    }
+    fn heartbeat(&mut self, token: u64) {
+        if let Some((_, timer)) = self.watching.get_mut(&token) {
+            timer.reset();
+        }
+    }
+    // Forget spectators who stopped sending heartbeats, and stop following players who are gone
+    fn update(&mut self, delta: Duration, player_states: &HashMap<u8, PlayerState>) {
+        for (spectator, timer) in self.watching.values_mut() {
+            timer.update(delta);
+            if timer.ready {
+                println!("Spectator {} disconnected", spectator.name);
+            }
+            if let Some(id) = spectator.following {
+                if !player_states.contains_key(&id) {
+                    spectator.following = None;
+                }
+            }
+        }
+        self.watching.retain(|_, (_, timer)| !timer.ready);
+    }
+    fn list(&self) -> Vec<Spectator> {
+        self.watching
+            .values()
+            .map(|(spectator, _)| spectator.clone())
+            .collect()
+    }
+}
+
+// What names players are allowed to use
+struct NameRules {
+    // Nobody can use these, or anything that looks like them
+    reserved: Vec<String>,
+    // Names can't have any of these in them, or anything that looks like them
+    blocklist: Vec<String>,
+    // Besides letters, numbers and spaces, names can have these characters in them
+    punctuation: String,
+}
+
+impl NameRules {
+    fn new() -> Self {
+        Self {
+            reserved: ["admin", "server", "moderator", "grue", "nobody"]
+                .iter()
+                .map(|name| canonical_name(name))
+                .collect(),
+            blocklist: Vec::new(),
+            punctuation: "-_.'".to_string(),
+        }
+    }
+    fn load_blocklist(&mut self, path: &str) {
+        self.blocklist
+            .extend(load_word_list(path, "name blocklist"));
+    }
+    // The reserved names in the file replace the usual ones
+    fn load_reserved(&mut self, path: &str) {
+        self.reserved = load_word_list(path, "reserved names");
+    }
+    // Tidy up the spacing in the name, and make sure it's one we're willing to show everyone.
+    // Returns the tidied-up name.
+    fn check(
+        &self,
+        name: &str,
+        player_states: &HashMap<u8, PlayerState>,
+        game_settings: &GameSettings,
+    ) -> Result<String, String> {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        let length = name.chars().count();
+        if length < game_settings.min_name_length || length > game_settings.max_name_length {
+            return Err(format!(
+                "Names need to be from {} to {} characters long.",
+                game_settings.min_name_length, game_settings.max_name_length
+            ));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|&c| !(c.is_alphanumeric() || c == ' ' || self.punctuation.contains(c)))
+        {
+            return Err(if self.punctuation.is_empty() {
+                format!(
+                    "Names can only have letters, numbers and spaces in them, not {:?}.",
+                    c
+                )
+            } else {
+                format!(
+                    "Names can only have letters, numbers, spaces and {} in them, not {:?}.",
+                    self.punctuation, c
+                )
+            });
+        }
+        let canonical = canonical_name(&name);
+        if canonical.is_empty() {
+            return Err("Names need at least one letter or number in them.".to_string());
+        }
+        if self.reserved.contains(&canonical) {
+            return Err(format!("The name \"{}\" is reserved.", name));
+        }
+        if self.blocklist.iter().any(|word| canonical.contains(word)) {
+            return Err(format!("The name \"{}\" isn't allowed.", name));
+        }
+        if player_states
+            .values()
+            .any(|player_state| canonical_name(&player_state.name) == canonical)
+        {
+            return Err(format!(
+                "Name \"{}\" is already taken (or one too much like it).",
+                name
+            ));
+        }
+        Ok(name)
+    }
+}
+
+// One word (or phrase) per line, looked at the way `canonical_name()` looks at names.  Blank lines
+// and lines starting with # are skipped.
+fn load_word_list(path: &str, what: &str) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(canonical_name)
+            .filter(|word| !word.is_empty())
+            .collect(),
+        Err(err) => {
+            println!("Failed to load {} {}: {}", what, path, err);
+            process::exit(2);
+        }
+    }
+}
+
+// Names that only differ by case, spacing, punctuation, or characters that look alike (like a
+// Latin "a" and a Cyrillic "а", or "0" and "O") count as the same name.  This is only for keeping
+// players from pretending to be each other -- "Alice" and "Aiice" are still different players.
+fn canonical_name(name: &str) -> String {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .filter(|c| c.is_alphanumeric())
+        .map(|c| match c {
+            'а' | '4' => 'a',
+            'в' => 'b',
+            'с' => 'c',
+            'е' | 'ё' | '3' => 'e',
+            'і' | 'ї' | 'l' | '1' => 'i',
+            'к' => 'k',
+            'м' => 'm',
+            'н' => 'h',
+            'о' | '0' => 'o',
+            'р' => 'p',
+            'ѕ' | '5' => 's',
+            'т' | '7' => 't',
+            'х' => 'x',
+            'у' => 'y',
+            _ => c,
+        })
+        .collect()
+}
+
+// Who a player is, as far as the high scores go: the identity they set, or their name (ignoring
+// case and spacing) if they didn't set one.  The two are kept apart, so nobody can claim someone
+// else's scores by joining with a name that happens to be their identity (or the other way around).
+fn identity_of(identity: Option<&str>, name: &str) -> String {
+    match identity {
+        Some(identity) => format!("identity:{}", identity),
+        None => format!(
+            "name:{}",
+            name.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+        ),
+    }
+}
+
+// Titles get trimmed, and can't be too long or have anything unprintable in them.  A blank title
+// is the same as no title.
+fn check_title(
+    title: Option<String>,
+    game_settings: &GameSettings,
+) -> Result<Option<String>, String> {
+    let title = match title {
+        Some(title) => title.trim().to_string(),
+        None => return Ok(None),
+    };
+    if title.is_empty() {
+        return Ok(None);
+    }
+    if title.chars().count() > game_settings.max_title_length {
+        return Err(format!(
+            "Titles can't be longer than {} characters.",
+            game_settings.max_title_length
+        ));
+    }
+    if title.chars().any(char::is_control) {
+        return Err("Titles can't have control characters in them.".to_string());
+    }
+    Ok(Some(title))
+}
+
+// What gets saved in the high scores file
+#[derive(Serialize, Deserialize)]
+struct SavedScores {
+    season: u32,
+    high_scores: HighScores,
+    // Which key goes with which identity.  See `Scoreboard.keys`.
+    keys: HashMap<String, u64>,
+}
+
+// Where the high scores are kept between runs of the server
+struct ScoreFile {
+    path: String,
+    // Put the old scores aside and start over instead of picking up where we left off
+    new_season: bool,
+}
+
+impl ScoreFile {
+    // If there isn't a file yet, then this is the very first season.  If we're starting a new
+    // season, the old file gets renamed to something like `high_scores.json.season-3` so it's
+    // not lost.
+    fn load(&self) -> SavedScores {
+        let result = match fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(SavedScores {
+                season: 0,
+                high_scores: HighScores::new(),
+                keys: HashMap::new(),
+            }),
+            Err(e) => Err(e.to_string()),
+        };
+        let mut saved = match result {
+            Ok(saved) => saved,
+            Err(err) => {
+                println!("Failed to load high scores {}: {}", self.path, err);
+                process::exit(2);
+            }
+        };
+        if saved.season == 0 || self.new_season {
+            if saved.season > 0 {
+                let archive = format!("{}.season-{}", self.path, saved.season);
+                if let Err(err) = fs::rename(&self.path, &archive) {
+                    println!("Failed to move old high scores to {}: {}", archive, err);
+                    process::exit(2);
+                }
+                println!("Season {} high scores moved to {}", saved.season, archive);
+            }
+            saved.season += 1;
+            saved.high_scores = HighScores::new();
+            saved.keys = HashMap::new();
+        }
+        saved
+    }
+    // Write everything to a temporary file and then swap it in, so a crash in the middle of a
+    // save can't leave us with a half-written file.
+    fn save(&self, season: u32, scoreboard: &Scoreboard) -> io::Result<()> {
+        let saved = SavedScores {
+            season,
+            high_scores: scoreboard.high_scores.clone(),
+            keys: scoreboard.keys.clone(),
+        };
+        let temp_path = format!("{}.tmp", self.path);
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(serde_json::to_string_pretty(&saved)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+// Award points to whoever holds each hill by themselves
+fn update_hills(
+    hills: &mut [Hill],
+    player_states: &HashMap<u8, PlayerState>,
+    delta: Duration,
+    scoreboard: &mut Scoreboard,
+) {
+    for hill in hills.iter_mut() {
+        let mut occupants = player_states
+            .values()
+            .filter(|player_state| !player_state.dead && hill.region.contains(&player_state.pos));
+        let holder = match (occupants.next(), occupants.next()) {
+            (Some(player_state), None) => Some(player_state.id),
+            _ => None,
+        };
+        if holder != hill.holder {
+            hill.holder = holder;
+            hill.point_timer.reset();
+        }
+        hill.point_timer.update(delta);
+        if let Some(holder) = hill.holder.and_then(|id| player_states.get(&id)) {
+            if hill.point_timer.ready {
+                hill.point_timer.reset();
+                scoreboard.score_objective(holder);
+            }
+        }
+    }
+}
+
+// Carry, drop, return and capture flags
+fn update_flags(
+    flags: &mut [Flag],
+    player_states: &mut HashMap<u8, PlayerState>,
+    game_settings: &GameSettings,
+    delta: Duration,
+    scoreboard: &mut Scoreboard,
+) {
+    for flag in flags.iter_mut() {
+        flag.update(delta);
+        match flag.carrier {
+            Some(carrier_id) => match player_states.get_mut(&carrier_id) {
+                // The flag goes wherever its carrier goes
+                Some(carrier) if !carrier.dead => flag.pos = carrier.pos,
+                // The carrier died or left, so the flag falls where they were.  If they were
+                // eaten by a grue, nobody is going to get it back out of the dark.
+                carrier => {
+                    if let Some(carrier) = carrier {
+                        carrier.flag = None;
+                        carrier
+                            .player_events
+                            .push(PlayerEvent::FlagDrop { team: flag.team });
+                    }
+                    println!("Team {}'s flag was dropped", flag.team);
+                    if game_settings.map.is_open(&flag.pos) {
+                        flag.put_down();
+                    } else {
+                        flag.go_home();
+                    }
+                }
+            },
+            None => {
+                if !flag.is_home() && flag.return_timer.ready {
+                    println!("Team {}'s flag went home by itself", flag.team);
+                    flag.go_home();
+                }
+            }
+        }
+    }
+    for player_state in player_states.values_mut() {
+        if player_state.dead {
+            continue;
+        }
+        let team = match player_state.team {
+            Some(team) => team,
+            None => continue,
+        };
+        let touching: Vec<u8> = flags
+            .iter()
+            .filter(|flag| flag.carrier.is_none() && flag.touches(player_state))
+            .map(|flag| flag.team)
+            .collect();
+        for flag_team in touching {
+            if flag_team == team {
+                // Your own flag: send it home if it was dropped, or capture the flag you're
+                // carrying if it's already home
+                if !flags[flag_team as usize].is_home() {
+                    flags[flag_team as usize].go_home();
+                    player_state
+                        .player_events
+                        .push(PlayerEvent::FlagReturn { team: flag_team });
+                    println!("{} sent team {}'s flag home", player_state.name, flag_team);
+                } else if let Some(captured) = player_state.flag.take() {
+                    flags[captured as usize].go_home();
+                    player_state
+                        .player_events
+                        .push(PlayerEvent::FlagCapture { team: captured });
+                    scoreboard.score_objective(player_state);
+                    println!("{} captured team {}'s flag!", player_state.name, captured);
+                }
+            } else if player_state.flag.is_none() {
+                // Somebody else's flag: grab it
+                flags[flag_team as usize].carrier = Some(player_state.id);
+                player_state.flag = Some(flag_team);
+                player_state
+                    .player_events
+                    .push(PlayerEvent::FlagPickup { team: flag_team });
+                println!("{} picked up team {}'s flag", player_state.name, flag_team);
+            }
+        }
+    }
+}
+
+fn update_projectiles(
+    projectiles: &mut Projectiles,
+    player_states: &mut HashMap<u8, PlayerState>,
+    game_settings: &GameSettings,
+    delta: Duration,
+    scoreboard: &mut Scoreboard,
+) {
+    let mut still_flying = Vec::<Projectile>::new();
+    for mut projectile in projectiles.in_flight.drain(..) {
+        let previous_pos = projectile.update(delta);
+        // Projectiles that run out of steam are gone
+        if projectile.lifetime.ready {
+            if let Some(shooter) = player_states.get_mut(&projectile.owner) {
+                shooter.player_events.push(PlayerEvent::AttackMiss);
+            }
+            continue;
+        }
+        // Projectiles stop at the first obstacle (or the edge of the arena) in their path
+        let blocked_at = game_settings.map.blocked_at(&previous_pos, &projectile.pos);
+        // Did the projectile pass through anyone along the way?  The first one it reaches gets
+        // hit.
+        let shooter = player_states.get(&projectile.owner);
+        let shooter_team = shooter.and_then(|shooter| shooter.team);
+        let shooter_key = shooter.map(|shooter| shooter.key);
+        let mut hit: Option<(u8, f32)> = None;
+        for (&defender_id, defender) in player_states.iter() {
+            // Dead or protected players don't defend, you can't shoot yourself, and dodging
+            // players are too quick to hit.  Without friendly fire, projectiles fly right past
+            // teammates.
+            if defender.dead
+                || defender.is_protected()
+                || defender_id == projectile.owner
+                || defender.is_dodging()
+            {
+                continue;
+            }
+            if !game_settings.friendly_fire
+                && shooter_team.is_some()
+                && defender.team == shooter_team
+            {
+                continue;
+            }
+            if let Some(t) = projectile.swept_hit(&previous_pos, &defender.pos, defender.radius) {
+                let nearer = match (hit, blocked_at) {
+                    (Some((_, nearest)), _) if nearest <= t => false,
+                    (_, Some(blocked_at)) => t < blocked_at,
+                    _ => true,
+                };
+                if nearer {
+                    hit = Some((defender_id, t));
+                }
+            }
+        }
+        let defender_id = match (hit, blocked_at) {
+            (Some((defender_id, _)), _) => defender_id,
+            // Flew into an obstacle or off into the dark
+            (None, Some(_)) => {
+                if let Some(shooter) = player_states.get_mut(&projectile.owner) {
+                    shooter.player_events.push(PlayerEvent::AttackMiss);
+                }
+                continue;
+            }
+            (None, None) => {
+                still_flying.push(projectile);
+                continue;
+            }
+        };
+        let mut shooter_events = Vec::<PlayerEvent>::new();
+        if let Some(defender) = player_states.get_mut(&defender_id) {
+            let mut damage = projectile.damage;
+            let mut knockback = projectile.knockback;
+            // The projectile came from back along its path
+            let from = defender.pos - projectile.velocity;
+            match defend(defender, &from, game_settings) {
+                Defense::Parried => {
+                    defender.player_events.push(PlayerEvent::Parry {
+                        id: projectile.owner,
+                    });
+                    shooter_events.push(PlayerEvent::AttackParried { id: defender_id });
+                    damage = 0.0;
+                    knockback = 0.0;
+                }
+                Defense::Blocked => {
+                    defender.player_events.push(PlayerEvent::Block {
+                        id: projectile.owner,
+                    });
+                    shooter_events.push(PlayerEvent::AttackBlocked { id: defender_id });
+                    damage *= game_settings.block_damage_multiplier;
+                    knockback *= game_settings.block_damage_multiplier;
+                }
+                Defense::Dodged | Defense::Unblocked => {}
+            }
+            if defender.has_buff(PickupKind::Shield) {
+                damage *= game_settings.shield_damage_multiplier;
+            }
+            if damage > 0.0 {
+                defender.health -= damage;
+                scoreboard.record_damage(
+                    defender,
+                    shooter_key,
+                    DeathCause::Player {
+                        killer: projectile.owner,
+                        weapon: projectile.kind,
+                    },
+                    damage,
+                    game_settings,
+                );
+                defender.apply_knockback(projectile.velocity.normalize() * knockback);
+                defender.player_events.push(PlayerEvent::HitByProjectile {
+                    id: projectile.owner,
+                });
+                defender.player_events.push(PlayerEvent::TookDamage);
+                shooter_events.push(PlayerEvent::AttackHit { id: defender_id });
+                println!(
+                    "Player {} shoots ({}) for {:2.1} damage bringing him to {} health.",
+                    projectile.owner, defender_id, damage, defender.health
+                );
+            }
+        }
+        // The shooter may have left while the projectile was in flight
+        if let Some(shooter) = player_states.get_mut(&projectile.owner) {
+            shooter.player_events.append(&mut shooter_events);
+        }
+    }
+    projectiles.in_flight = still_flying;
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_state(
     player_states: &mut HashMap<u8, PlayerState>,
     player_inputs: &mut HashMap<u8, PlayerInput>,
+    projectiles: &mut Projectiles,
     game_settings: &mut GameSettings,
-    color_picker: &mut ColorPicker,
+    sessions: &mut Sessions,
     delta: Duration,
     rng: &mut ThreadRng,
-    high_scores: &mut HighScores,
+    scoreboard: &mut Scoreboard,
+    zone: Option<&SafeZone>,
 ) {
     let delta_f32 = delta.f32();
+    scoreboard.kills.update(delta);
 
     // Update player timers, spawn anyone who is ready
     // See if any players disconnect, die, or spawn
+    let living: Vec<(Option<u8>, Vec2)> = player_states
+        .values()
+        .filter(|player_state| !player_state.dead)
+        .map(|player_state| (player_state.team, player_state.pos))
+        .collect();
     for (id, player_state) in player_states.iter_mut() {
         // First update any delta-dependent state
         player_state.update(delta);
-        // Anyone ready to spawn?
-        if player_state.dead && player_state.respawn_timer.ready {
-            player_state.respawn(new_in_square(0.9, rng), &format!("Player {} spawns", id));
+        // Catch your breath, unless you're holding up a block
+        if !player_state.blocking {
+            player_state.stamina = (player_state.stamina + game_settings.stamina_regen * delta_f32)
+                .min(game_settings.max_stamina);
+        }
+        // Anyone ready to spawn?  Spawn them as far from everyone else as we can.
+        if player_state.dead
+            && !player_state.afk
+            && player_state.respawn_timer.ready
+            && scoreboard.round.allows_respawn(game_settings.game_mode)
+        {
+            // Teammates aren't a threat
+            let enemy_positions: Vec<Vec2> = living
+                .iter()
+                .filter(|(team, _)| team.is_none() || *team != player_state.team)
+                .map(|(_, pos)| *pos)
+                .collect();
+            let pos = game_settings.map.safest_spawn(rng, &enemy_positions, zone);
+            player_state.respawn(pos, &format!("Player {} spawns", id));
         }
     }
 
     // Process input to affect velocities
-    for (id, player_input) in &mut player_inputs.iter() {
+    for (id, player_input) in player_inputs.iter_mut() {
         if let Some(player_state) = player_states.get_mut(id) {
             // Ignore input from dead players, and remove their movement.
             if player_state.dead {
                 player_state.velocity = Vec2::zeros();
+                player_input.dodge = false;
                 continue;
             }
             // Instantaneously face a direction
             player_state.direction = player_input.direction;
+            // Hold up a block for as long as you have the stamina for it
+            let was_blocking = player_state.blocking;
+            player_state.blocking = player_input.block && player_state.stamina > 0.0;
+            if player_state.blocking && !was_blocking {
+                player_state.parry_timer.reset();
+            }
+            // Dodge in the direction you're moving, or forward if you're holding still.  The dodge
+            // is an impulse, just like getting knocked back.
+            if player_input.dodge
+                && !player_state.is_dodging()
+                && player_state.stamina >= game_settings.dodge_cost
+            {
+                let dodge_direction =
+                    if player_input.move_amount.magnitude() > game_settings.move_threshold {
+                        player_input.move_amount.normalize()
+                    } else {
+                        Vec2::new(player_state.direction.cos(), player_state.direction.sin())
+                    };
+                player_state.stamina -= game_settings.dodge_cost;
+                player_state.dodge_timer.reset();
+                player_state.apply_knockback(dodge_direction * game_settings.dodge_speed);
+                player_state.player_events.push(PlayerEvent::Dodge);
+            }
+            player_input.dodge = false;
             // Update current velocity
             let clamped_move_amount = if player_input.move_amount.magnitude() > 1.0 {
                 player_input.move_amount.normalize()
@@ -562,39 +2883,95 @@ fn update_state(
                 player_state.velocity =
                     player_state.velocity * (1.0 - (game_settings.drag * delta_f32));
             }
-            // If the player is attacking, then he can only go half as fast
+            // If the player is attacking, then he is slowed down by his weapon.  Carrying a flag
+            // slows you down, too.
+            let mut max_velocity = game_settings.max_velocity;
             if player_input.attack {
-                // player_state.velocity = player_state
-                //     .velocity
-                //     .clamped_to(game_settings.max_velocity * 0.5);
-                clamp_vec_to_magnitude(&mut player_state.velocity, game_settings.max_velocity * 0.5)
-            } else {
-                clamp_vec_to_magnitude(&mut player_state.velocity, game_settings.max_velocity);
+                max_velocity *= player_state.weapon.attack_speed_multiplier;
             }
+            if player_state.flag.is_some() {
+                max_velocity *= game_settings.flag_carry_speed_multiplier;
+            }
+            if player_state.has_buff(PickupKind::SpeedBoost) {
+                max_velocity *= game_settings.speed_boost_multiplier;
+            }
+            clamp_vec_to_magnitude(&mut player_state.velocity, max_velocity);
         }
     }
     // Process all the velocities to affect position (not just the players who had input this loop)
-    for (id, player_state) in player_states.iter_mut() {
+    for player_state in player_states.values_mut() {
         // Dead players don't move
         if player_state.dead {
             continue;
         }
-        // Apply velocity to position
-        player_state.pos = player_state.pos + (player_state.velocity * delta_f32);
+        // Apply velocity (and any knockback) to position
+        player_state.pos += (player_state.velocity + player_state.knockback) * delta_f32;
+        // Knockback drags back down to nothing
+        player_state.knockback *= (1.0 - (game_settings.drag * delta_f32)).max(0.0);
+    }
+
+    // Players can't walk through each other, or through obstacles
+    resolve_collisions(player_states);
+    resolve_obstacles(player_states, &game_settings.map);
+
+    let mut eaten_by_grue = Vec::<u8>::new();
+    for (id, player_state) in player_states.iter_mut() {
+        if player_state.dead {
+            continue;
+        }
+        // Standing in a hazard hurts (unless you just spawned)
+        let mut hazard_damage = 0.0;
+        for hazard in &game_settings.map.hazards {
+            if !player_state.is_protected() && hazard.region.contains(&player_state.pos) {
+                hazard_damage += hazard.damage_per_second * delta_f32;
+            }
+        }
+        if hazard_damage > 0.0 {
+            scoreboard.record_damage(
+                player_state,
+                None,
+                DeathCause::Hazard,
+                hazard_damage,
+                game_settings,
+            );
+        }
+        // So does getting caught outside of the safe zone (unless you just spawned)
+        let mut zone_damage = 0.0;
+        if let Some(zone) = zone {
+            if !player_state.is_protected() && !zone.contains(&player_state.pos) {
+                zone_damage = zone.damage_per_second(&game_settings.zone_phases) * delta_f32;
+                scoreboard.record_damage(
+                    player_state,
+                    None,
+                    DeathCause::Zone,
+                    zone_damage,
+                    game_settings,
+                );
+            }
+        }
+        if hazard_damage + zone_damage > 0.0 {
+            player_state.health -= hazard_damage + zone_damage;
+            if !player_state
+                .player_events
+                .contains(&PlayerEvent::TookDamage)
+            {
+                player_state.player_events.push(PlayerEvent::TookDamage);
+            }
+        }
         // Don't go all the way into the dark!
-        let boundary = 1.0;
-        if player_state.pos.x < -boundary
-            || player_state.pos.x > boundary
-            || player_state.pos.y < -boundary
-            || player_state.pos.y > boundary
-        {
-            high_scores.penalize(&player_state.name);
-            player_state.die(&format!(
-                "Player {} was eaten by a grue.  Should have stayed in the light!",
-                id
-            ));
+        if !game_settings.map.boundary.contains(&player_state.pos) {
+            eaten_by_grue.push(*id);
         }
     }
+    for id in eaten_by_grue {
+        kill_player(
+            id,
+            DeathCause::Grue,
+            player_states,
+            game_settings,
+            scoreboard,
+        );
+    }
 
     // Get everyone who wants to attack
     let mut attacking_ids: Vec<u8> = vec![];
@@ -619,34 +2996,101 @@ fn update_state(
             player_states.insert(id, attacker);
             continue;
         }
-        // You can only attack so often
-        if !attacker.weapon.attack_timer.ready {
+        // You can only attack so often, and not while blocking
+        if !attacker.weapon.attack_timer.ready || attacker.blocking {
             player_states.insert(id, attacker);
             continue;
         }
-        // Actually attack defenders
         attacker.weapon.attack_timer.reset();
+        // Attacking gives up your spawn protection
+        attacker.spawn_protection_timer.finish();
+        // Ranged weapons fire a projectile instead of swinging
+        if attacker.weapon.projectile.is_some() {
+            projectiles.fire(&mut attacker, game_settings);
+            player_states.insert(id, attacker);
+            continue;
+        }
+        // Actually attack defenders
         let mut missed = true;
         for (&defender_id, defender) in player_states.iter_mut() {
-            // Dead players don't defend
-            if defender.dead {
+            // Dead or protected players don't defend
+            if defender.dead || defender.is_protected() {
+                continue;
+            }
+            // Without friendly fire, you swing right past your teammates
+            if attacker.is_teammate(defender) && !game_settings.friendly_fire {
+                continue;
+            }
+            if distance(&attacker.pos, &defender.pos) > attacker.weapon.radius + attacker.radius {
+                continue;
+            }
+            // Only hit defenders within the swing of the weapon
+            if !attacker.weapon.in_arc(
+                attacker.direction,
+                angle_facing(&attacker.pos, &defender.pos),
+            ) {
                 continue;
             }
-            if distance(&attacker.pos, &defender.pos) <= attacker.weapon.radius + attacker.radius {
-                missed = false;
-                if (defender.health > 0.0) && ((defender.health - attacker.weapon.damage) <= 0.0) {
-                    high_scores.score(&attacker.name);
+            let mut damage = attacker.weapon.damage;
+            let mut knockback = attacker.weapon.knockback;
+            if attacker.has_buff(PickupKind::DamageBuff) {
+                damage *= game_settings.damage_buff_multiplier;
+            }
+            if defender.has_buff(PickupKind::Shield) {
+                damage *= game_settings.shield_damage_multiplier;
+            }
+            match defend(defender, &attacker.pos, game_settings) {
+                Defense::Dodged => continue,
+                Defense::Parried => {
+                    missed = false;
+                    attacker
+                        .weapon
+                        .attack_timer
+                        .set_millis_transient(game_settings.parry_penalty);
+                    attacker
+                        .player_events
+                        .push(PlayerEvent::AttackParried { id: defender_id });
+                    defender.player_events.push(PlayerEvent::Parry { id });
+                    println!("Player {} parries ({})", defender_id, id);
+                    continue;
                 }
-                defender.health -= attacker.weapon.damage;
-                attacker
-                    .player_events
-                    .push(PlayerEvent::AttackHit { id: defender_id });
-                defender.player_events.push(PlayerEvent::TookDamage);
-                println!(
-                    "Player {} swings and hits ({}) for {:2.1} damage bringing him to {} health.",
-                    id, defender_id, attacker.weapon.damage, defender.health
-                );
+                Defense::Blocked => {
+                    attacker
+                        .player_events
+                        .push(PlayerEvent::AttackBlocked { id: defender_id });
+                    defender.player_events.push(PlayerEvent::Block { id });
+                    damage *= game_settings.block_damage_multiplier;
+                    knockback *= game_settings.block_damage_multiplier;
+                }
+                Defense::Unblocked => {}
             }
+            missed = false;
+            defender.health -= damage;
+            scoreboard.record_damage(
+                defender,
+                Some(attacker.key),
+                DeathCause::Player {
+                    killer: id,
+                    weapon: attacker.weapon.kind,
+                },
+                damage,
+                game_settings,
+            );
+            // Shove the defender away from the attacker
+            let shove_direction = if distance(&attacker.pos, &defender.pos) > 0.0 {
+                (defender.pos - attacker.pos).normalize()
+            } else {
+                Vec2::new(attacker.direction.cos(), attacker.direction.sin())
+            };
+            defender.apply_knockback(shove_direction * knockback);
+            attacker
+                .player_events
+                .push(PlayerEvent::AttackHit { id: defender_id });
+            defender.player_events.push(PlayerEvent::TookDamage);
+            println!(
+                "Player {} swings and hits ({}) for {:2.1} damage bringing him to {} health.",
+                id, defender_id, damage, defender.health
+            );
         }
         if missed {
             attacker.player_events.push(PlayerEvent::AttackMiss);
@@ -654,20 +3098,525 @@ fn update_state(
         player_states.insert(id, attacker);
     }
 
-    // See if any players disconnect or die
+    // Fly projectiles, and see who they hit
+    update_projectiles(projectiles, player_states, game_settings, delta, scoreboard);
+
+    // See if any players disconnect
     let to_process = player_states.drain().collect::<Vec<(u8, PlayerState)>>();
     for (id, mut player_state) in to_process {
-        // Mark any player for disconnection who stopped sending us input for too long
+        // Set aside any player who stopped sending us heartbeats, in case they come back
         if player_state.drop_timer.ready {
-            remove_player(id, player_states, color_picker, true);
+            scoreboard.record_time_alive(&player_state);
+            player_state.time_alive = Duration::from_secs(0);
+            sessions.disconnect(player_state, game_settings);
             continue;
         }
-        // Anyone alive whose health went negative dies
-        if !player_state.dead && player_state.health <= 0.0 {
-            player_state.die(&format!("Player {} dies from his wounds.", id));
-        }
         player_states.insert(id, player_state);
     }
+
+    // Anyone alive whose health went negative dies, and whoever hurt them last gets the credit
+    let dying: Vec<u8> = player_states
+        .values()
+        .filter(|player_state| !player_state.dead && player_state.health <= 0.0)
+        .map(|player_state| player_state.id)
+        .collect();
+    for id in dying {
+        // Everything that hurts gets recorded, so there should always be a cause.  Just in case,
+        // blame the arena.
+        let cause = scoreboard
+            .kills
+            .last_cause(id)
+            .unwrap_or(DeathCause::Hazard);
+        kill_player(id, cause, player_states, game_settings, scoreboard);
+    }
+}
+
+// Someone waiting in the matchmaking queue
+struct QueueEntry {
+    name: String,
+    // Who they are, just like when they join.  See `identity_of()`.
+    who: String,
+    // The match room they want to play in, if they care
+    room: Option<u8>,
+    waited: Duration,
+    drop_timer: timer::Timer,
+    status: QueueStatus,
+}
+
+// The players the lobby matched up to play in a match room.  Each of them gets a join ticket, and
+// nobody else can join it until everyone is gone.
+struct Reservation {
+    // The join tickets nobody has used yet
+    tickets: Vec<u64>,
+    timer: timer::Timer,
+}
+
+// Players waiting to be matched up with players of about the same skill, by ticket
+struct Lobby {
+    // How long players wait for a match before we give up
+    timeout: Duration,
+    queue: HashMap<u64, QueueEntry>,
+}
+
+impl Lobby {
+    fn new() -> Self {
+        Self {
+            timeout: Duration::from_secs(120),
+            queue: HashMap::new(),
+        }
+    }
+    fn add(
+        &mut self,
+        name: &str,
+        identity: Option<String>,
+        room: Option<u8>,
+        arenas: &[Arena],
+        name_rules: &NameRules,
+        rng: &mut ThreadRng,
+    ) -> Result<u64, String> {
+        let match_room = match room {
+            Some(room) => arenas
+                .get(room as usize)
+                .filter(|arena| arena.game_settings.match_size > 0)
+                .ok_or_else(|| format!("Queue Failed: Room {} isn't a match room.", room))?,
+            None => arenas
+                .iter()
+                .find(|arena| arena.game_settings.match_size > 0)
+                .ok_or_else(|| "Queue Failed: This server doesn't run any matches.".to_string())?,
+        };
+        let name = name_rules
+            .check(name, &HashMap::new(), &match_room.game_settings)
+            .map_err(|err| format!("Queue Failed: {}", err))?;
+        let who = identity_of(identity.as_deref(), &name);
+        if self
+            .queue
+            .values()
+            .any(|entry| entry.who == who && matches!(entry.status, QueueStatus::Waiting { .. }))
+        {
+            return Err(format!("Queue Failed: \"{}\" is already in line.", name));
+        }
+        println!("{} is waiting for a match", name);
+        let ticket = rng.gen::<u64>();
+        self.queue.insert(
+            ticket,
+            QueueEntry {
+                name,
+                who,
+                room,
+                waited: Duration::from_secs(0),
+                drop_timer: timer::Timer::from_millis(QUEUE_DROP_DELAY),
+                status: QueueStatus::Waiting {
+                    position: 0,
+                    queued: 0,
+                    waited: 0,
+                },
+            },
+        );
+        Ok(ticket)
+    }
+    // Players who got matched (or gave up on) only hear about it once
+    fn status(&mut self, ticket: u64) -> Result<QueueStatus, String> {
+        let entry = self.queue.get_mut(&ticket).ok_or_else(|| {
+            "You aren't in the queue.  Maybe you stopped checking in?".to_string()
+        })?;
+        entry.drop_timer.reset();
+        let waited = entry.waited;
+        if let QueueStatus::Waiting { .. } = entry.status {
+            // Whoever has waited the longest is first in line
+            let waiting = self
+                .queue
+                .values()
+                .filter(|entry| matches!(entry.status, QueueStatus::Waiting { .. }));
+            let (ahead, queued) = waiting.fold((0, 0), |(ahead, queued), entry| {
+                (ahead + (entry.waited > waited) as u16, queued + 1)
+            });
+            return Ok(QueueStatus::Waiting {
+                position: ahead + 1,
+                queued,
+                waited: waited.as_millis() as u64,
+            });
+        }
+        Ok(self.queue.remove(&ticket).unwrap().status)
+    }
+    fn remove(&mut self, ticket: u64) -> bool {
+        match self.queue.remove(&ticket) {
+            Some(entry) => {
+                println!("{} stopped waiting for a match", entry.name);
+                true
+            }
+            None => false,
+        }
+    }
+    fn update(&mut self, delta: Duration, arenas: &mut [Arena], rng: &mut ThreadRng) {
+        // Give up on players who have waited too long, and forget players who stopped checking in
+        for entry in self.queue.values_mut() {
+            entry.drop_timer.update(delta);
+            if let QueueStatus::Waiting { .. } = entry.status {
+                entry.waited += delta;
+                if entry.waited >= self.timeout {
+                    println!("{} couldn't find a match", entry.name);
+                    entry.status = QueueStatus::TimedOut;
+                }
+            }
+        }
+        self.queue.retain(|_, entry| !entry.drop_timer.ready);
+
+        // Match rooms open back up once the last match is over
+        for arena in arenas.iter_mut() {
+            if let Some(reservation) = arena.reservation.as_mut() {
+                reservation.timer.update(delta);
+                if reservation.timer.ready
+                    && arena.player_states.is_empty()
+                    && arena.sessions.disconnected.is_empty()
+                {
+                    println!("Room {} is open for a new match", arena.name);
+                    arena.reservation = None;
+                }
+            }
+        }
+
+        // Fill each open match room with the closest-rated group of players who want to play there
+        for arena in arenas.iter_mut() {
+            let match_size = arena.game_settings.match_size as usize;
+            if match_size == 0
+                || arena.reservation.is_some()
+                || !arena.player_states.is_empty()
+                || !arena.sessions.disconnected.is_empty()
+            {
+                continue;
+            }
+            let mut candidates: Vec<(u64, f32, Duration)> = self
+                .queue
+                .iter()
+                .filter(|(_, entry)| matches!(entry.status, QueueStatus::Waiting { .. }))
+                .filter(|(_, entry)| match entry.room {
+                    Some(room) => room == arena.room,
+                    None => true,
+                })
+                .map(|(&ticket, entry)| {
+                    let rating = arena.scoreboard.rating_of(&entry.who);
+                    (ticket, rating, entry.waited)
+                })
+                .collect();
+            if candidates.len() < match_size {
+                continue;
+            }
+            candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            let best = candidates
+                .windows(match_size)
+                .filter(|group| {
+                    let spread = group[match_size - 1].1 - group[0].1;
+                    let longest_wait = group.iter().map(|(_, _, waited)| *waited).max().unwrap();
+                    let growth = longest_wait.as_millis() as f32 / MATCH_SPREAD_GROWTH_DELAY as f32;
+                    spread <= arena.game_settings.match_rating_spread * (1.0 + growth.floor())
+                })
+                .min_by(|a, b| {
+                    let a_spread = a[match_size - 1].1 - a[0].1;
+                    let b_spread = b[match_size - 1].1 - b[0].1;
+                    a_spread.partial_cmp(&b_spread).unwrap()
+                });
+            let group: Vec<u64> = match best {
+                Some(group) => group.iter().map(|(ticket, _, _)| *ticket).collect(),
+                None => continue,
+            };
+            let mut tickets = Vec::new();
+            let mut names = Vec::new();
+            for ticket in group {
+                let entry = self.queue.get_mut(&ticket).unwrap();
+                let join_ticket = rng.gen::<u64>();
+                entry.status = QueueStatus::Matched {
+                    room: arena.room,
+                    join_ticket,
+                };
+                tickets.push(join_ticket);
+                names.push(entry.name.clone());
+            }
+            println!("Matched {} in room {}", names.join(", "), arena.name);
+            arena.reservation = Some(Reservation {
+                tickets,
+                timer: timer::Timer::from_millis(MATCH_JOIN_DELAY),
+            });
+        }
+    }
+}
+
+// One of the arenas (rooms) the server runs.  Each room is its own game, with its own settings,
+// players, spectators, chat and high scores.
+struct Arena {
+    room: u8,
+    name: String,
+    game_settings: GameSettings,
+    score_file: ScoreFile,
+    save_timer: timer::Timer,
+    color_picker: ColorPicker,
+    scoreboard: Scoreboard,
+    objectives: Objectives,
+    zone: Option<SafeZone>,
+    pickups: Pickups,
+    frame_number: u64,
+    player_states: HashMap<u8, PlayerState>,
+    player_inputs: HashMap<u8, PlayerInput>,
+    projectiles: Projectiles,
+    sessions: Sessions,
+    spectators: Spectators,
+    chat: Chat,
+    // Who the lobby matched up to play here, if this is a match room
+    reservation: Option<Reservation>,
+}
+
+impl Arena {
+    fn new(room: u8, room_config: RoomConfig, rng: &mut ThreadRng) -> Self {
+        let RoomConfig {
+            name,
+            mut game_settings,
+            score_file,
+        } = room_config;
+        let mut color_picker = ColorPicker::new();
+        let saved_scores = score_file.load();
+        game_settings.season = saved_scores.season;
+        let mut scoreboard = Scoreboard::new(&game_settings, &mut color_picker);
+        scoreboard.high_scores = saved_scores.high_scores;
+        scoreboard.keys = saved_scores.keys;
+        Self {
+            room,
+            name,
+            objectives: Objectives::new(&game_settings),
+            zone: SafeZone::new(&game_settings, rng),
+            pickups: Pickups::new(&game_settings),
+            game_settings,
+            score_file,
+            save_timer: timer::Timer::from_millis(SCORE_SAVE_DELAY),
+            color_picker,
+            scoreboard,
+            frame_number: 0,
+            player_states: HashMap::new(),
+            player_inputs: HashMap::new(),
+            projectiles: Projectiles::new(),
+            sessions: Sessions::new(),
+            spectators: Spectators::new(),
+            chat: Chat::new(),
+            reservation: None,
+        }
+    }
+    fn info(&self) -> RoomInfo {
+        RoomInfo {
+            room: self.room,
+            name: self.name.clone(),
+            game_mode: self.game_settings.game_mode,
+            players: playing_count(&self.player_states) as u8,
+            max_players: self.game_settings.max_players,
+            spectators: self.spectators().len() as u16,
+            match_size: self.game_settings.match_size,
+        }
+    }
+    // The ids of everyone here, including disconnected players we're holding a place for
+    fn ids(&self) -> Vec<u8> {
+        self.player_states
+            .keys()
+            .cloned()
+            .chain(
+                self.sessions
+                    .disconnected
+                    .values()
+                    .map(|(player_state, _)| player_state.id),
+            )
+            .collect()
+    }
+    fn has_player(&self, id: u8) -> bool {
+        self.player_states.contains_key(&id) || self.sessions.holds_id(id)
+    }
+    // Whether a resume token or spectator token belongs to someone in this room
+    fn has_token(&self, token: u64) -> bool {
+        self.sessions.tokens.values().any(|&t| t == token)
+            || self.sessions.disconnected.contains_key(&token)
+            || self.spectators.watching.contains_key(&token)
+    }
+    // Everyone watching, including players sitting out for being AFK
+    fn spectators(&self) -> Vec<Spectator> {
+        let sitting_out = self
+            .player_states
+            .values()
+            .filter(|player_state| player_state.afk)
+            .map(|player_state| Spectator {
+                name: player_state.name.clone(),
+                following: None,
+            });
+        self.spectators
+            .list()
+            .into_iter()
+            .chain(sitting_out)
+            .collect()
+    }
+    fn save_scores(&self) -> io::Result<()> {
+        self.score_file
+            .save(self.game_settings.season, &self.scoreboard)
+    }
+    // Everything that happens in the room each time around the main loop
+    fn update(&mut self, delta: Duration, rng: &mut ThreadRng) {
+        self.save_timer.update(delta);
+
+        // The darkness closes in, but only while the round is on
+        if self.scoreboard.round.phase == RoundPhase::Active {
+            if let Some(zone) = self.zone.as_mut() {
+                zone.update(delta, &self.game_settings.zone_phases, rng);
+            }
+        }
+        // Without rounds there's no next round to bring the light back, so once the darkness is
+        // done closing in, it starts over
+        if !self.game_settings.game_mode.has_rounds() {
+            let done = match self.zone.as_ref() {
+                Some(zone) => zone.is_done(&self.game_settings.zone_phases),
+                None => false,
+            };
+            if done {
+                println!("The darkness lifts in room {}", self.name);
+                self.zone = SafeZone::new(&self.game_settings, rng);
+            }
+        }
+
+        // Move, attack, etc.
+        update_state(
+            &mut self.player_states,
+            &mut self.player_inputs,
+            &mut self.projectiles,
+            &mut self.game_settings,
+            &mut self.sessions,
+            delta,
+            rng,
+            &mut self.scoreboard,
+            self.zone.as_ref(),
+        );
+
+        // Sit out or kick anyone who is AFK
+        update_afk(
+            &mut self.player_states,
+            &self.game_settings,
+            &mut self.sessions,
+            &mut self.color_picker,
+            &mut self.scoreboard,
+            &mut self.chat,
+        );
+
+        // Give up on anyone who hasn't reconnected in time
+        for player_state in self.sessions.update(delta) {
+            retire_player(
+                &player_state,
+                &mut self.color_picker,
+                &mut self.chat,
+                "never came back",
+            );
+        }
+        self.spectators.update(delta, &self.player_states);
+
+        // Put out new pickups, and pick up the ones players walk over
+        self.pickups.update(&self.game_settings, delta, rng);
+        collect_pickups(
+            &mut self.pickups,
+            &mut self.player_states,
+            &self.game_settings,
+        );
+
+        // Hold hills and capture flags
+        update_hills(
+            &mut self.objectives.hills,
+            &self.player_states,
+            delta,
+            &mut self.scoreboard,
+        );
+        update_flags(
+            &mut self.objectives.flags,
+            &mut self.player_states,
+            &self.game_settings,
+            delta,
+            &mut self.scoreboard,
+        );
+
+        // Start, end, and move between rounds
+        update_round(
+            &mut self.scoreboard,
+            &mut self.objectives,
+            &mut self.zone,
+            &self.game_settings,
+            &mut self.player_states,
+            delta,
+            rng,
+        );
+
+        // Save the high scores every so often
+        if self.save_timer.ready {
+            self.save_timer.reset();
+            if let Err(err) = self.save_scores() {
+                println!(
+                    "Failed to save high scores to {}: {}",
+                    self.score_file.path, err
+                );
+            }
+        }
+    }
+    // Broadcast the game state computed this frame to everyone in the room
+    fn send_frame(
+        &mut self,
+        game_state_server_socket: &zmq::Socket,
+        delta: Duration,
+        loop_iterations: i64,
+    ) {
+        // This frame's events are about to go out (and get cleared), so count them up first
+        self.scoreboard.record_attacks(&self.player_states);
+
+        // Everyone gets their own rank, since most of them won't be in the top 10
+        for player_state in self.player_states.values_mut() {
+            player_state.rank = self
+                .scoreboard
+                .high_scores
+                .rank(player_state.key)
+                .unwrap_or(0);
+        }
+
+        let top10 = self.scoreboard.high_scores.top10();
+        let mut round_top10 = self.scoreboard.round.clone();
+        round_top10.scores = self.scoreboard.round.scores.top10();
+        if self.frame_number % 1800 == 0 {
+            let status = format!(
+                "STATUS: Room: {}, Frame: {}, Loops during latest frame: {}, Round {} ({:?}), \
+                 Players: {}, Spectators: {}\n{}",
+                self.name,
+                self.frame_number,
+                loop_iterations,
+                self.scoreboard.round.number,
+                self.scoreboard.round.phase,
+                self.player_states.len(),
+                self.spectators.watching.len(),
+                top10
+            );
+            println!("{}", status);
+        }
+        let game_state = GameState {
+            frame_number: self.frame_number,
+            delta,
+            game_settings_hash: self.game_settings.get_hash(),
+            player_states: self.player_states.clone(),
+            high_scores: top10,
+            round: round_top10,
+            projectiles: self.projectiles.in_flight.clone(),
+            teams: self.scoreboard.teams.clone(),
+            hills: self.objectives.hills.clone(),
+            flags: self.objectives.flags.clone(),
+            kill_feed: self.scoreboard.kills.feed.clone(),
+            pickups: self.pickups.lying_around.clone(),
+            zone: self.zone.clone(),
+            spectators: self.spectators(),
+        };
+        game_state_server_socket
+            .send_multipart(
+                &[net::room_topic(self.room), serialize(&game_state).unwrap()],
+                0,
+            )
+            .unwrap();
+        for player_state in self.player_states.values_mut() {
+            player_state.new_frame();
+        }
+        self.frame_number += 1;
+    }
 }
 
 fn main() {
@@ -684,6 +3633,11 @@ fn main() {
         .bind(&format!("tcp://*:{}", net::GAME_STATE_PORT))
         .unwrap();
 
+    let chat_server_socket = ctx.socket(zmq::PUB).unwrap();
+    chat_server_socket
+        .bind(&format!("tcp://*:{}", net::CHAT_PORT))
+        .unwrap();
+
     let mut player_input_server_socket = ctx.socket(zmq::PULL).unwrap();
     player_input_server_socket.set_rcvtimeo(0).unwrap();
     player_input_server_socket
@@ -693,18 +3647,30 @@ fn main() {
     let mut loop_iterations: i64 = 0;
     let mut loop_start = Instant::now();
     let mut frame_timer = timer::Timer::from_nanos(16_666_666); // 60 FPS
-    let mut color_picker = ColorPicker::new();
-    let mut game_settings = GameSettings::new();
+    let mut name_rules = NameRules::new();
+    let mut lobby = Lobby::new();
     let mut rng = thread_rng();
-    let mut frame_number: u64 = 0;
-    let mut player_states = HashMap::<u8, PlayerState>::new();
-    let mut player_inputs = HashMap::<u8, PlayerInput>::new();
-    let mut high_scores = HighScores::new();
+    let mut arenas: Vec<Arena> = parse_args(&mut name_rules, &mut lobby)
+        .into_iter()
+        .enumerate()
+        .map(|(room, room_config)| Arena::new(room as u8, room_config, &mut rng))
+        .collect();
     let sleep_delay = Duration::from_millis(1);
 
+    // Ctrl-C stops the main loop instead of the whole process, so we get a chance to save
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = running.clone();
+    ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst)).unwrap();
+
     println!("--------------------------------------------------------------");
-    println!("Server started (Ctrl-C to stop)\n{:#?}", game_settings);
-    loop {
+    println!("Server started (Ctrl-C to stop)");
+    for arena in arenas.iter() {
+        println!(
+            "Room {} ({})\n{:#?}",
+            arena.room, arena.name, arena.game_settings
+        );
+    }
+    while running.load(Ordering::SeqCst) {
         let delta = loop_start.elapsed();
         loop_start = Instant::now();
         frame_timer.update(delta);
@@ -717,60 +3683,269 @@ fn main() {
         // Handle and reply to all Game Control requests. The game settings might get changed.
         process_game_control_requests(
             &mut game_control_server_socket,
-            &mut game_settings,
-            &mut player_states,
+            &mut arenas,
+            &mut lobby,
             &mut rng,
-            &mut color_picker,
-            &mut high_scores,
+            &name_rules,
         );
 
-        // Handle and coalesce all the player input we've received so far into player_inputs
-        coalesce_player_input(
-            &mut player_input_server_socket,
-            &mut player_states,
-            &mut player_inputs,
-        );
+        // Handle and coalesce all the player input we've received so far into each room's
+        // player_inputs
+        coalesce_player_input(&mut player_input_server_socket, &mut arenas);
 
-        // Move, attack, etc.
-        update_state(
-            &mut player_states,
-            &mut player_inputs,
-            &mut game_settings,
-            &mut color_picker,
-            delta,
-            &mut rng,
-            &mut high_scores,
-        );
+        // Match up players waiting in the lobby
+        lobby.update(delta, &mut arenas, &mut rng);
+
+        for arena in arenas.iter_mut() {
+            // Send out any chat messages we got
+            for message in arena.chat.outbox.drain(..) {
+                chat_server_socket
+                    .send_multipart(
+                        &[net::chat_topic(arena.room), serialize(&message).unwrap()],
+                        0,
+                    )
+                    .unwrap();
+            }
+
+            // Move, attack, score, etc.
+            arena.update(delta, &mut rng);
+        }
 
         // Process a frame (if it's time)
 
         if frame_timer.ready {
             frame_timer.reset();
-
-            let top10 = high_scores.top10();
-            // Broadcast new game state computed this frame
-            if frame_number % 1800 == 0 {
-                let status = format!(
-                    "STATUS: Frame: {}, Loops during latest frame: {}\n{}",
-                    frame_number, loop_iterations, top10
-                );
-                println!("{}", status);
-            }
-            let game_state = GameState {
-                frame_number,
-                delta,
-                game_settings_hash: game_settings.get_hash(),
-                player_states: player_states.clone(),
-                high_scores: top10,
-            };
-            game_state_server_socket
-                .send(&serialize(&game_state).unwrap(), 0)
-                .unwrap();
-            for player_state in player_states.values_mut() {
-                player_state.new_frame();
+            for arena in arenas.iter_mut() {
+                arena.send_frame(&game_state_server_socket, delta, loop_iterations);
             }
             loop_iterations = 0;
-            frame_number += 1;
         }
     }
+
+    for arena in arenas.iter() {
+        match arena.save_scores() {
+            Ok(()) => println!("High scores saved to {}", arena.score_file.path),
+            Err(err) => println!(
+                "Failed to save high scores to {}: {}",
+                arena.score_file.path, err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Match rooms for two players each, with high scores that never get saved
+    fn match_rooms(count: u8, rng: &mut ThreadRng) -> Vec<Arena> {
+        (0..count)
+            .map(|room| {
+                let score_path = env::temp_dir().join(format!("rsa-lobby-test-{}.json", room));
+                let mut room_config = RoomConfig::new("duel", score_path.to_str().unwrap());
+                room_config.game_settings.match_size = 2;
+                room_config.game_settings.match_rating_spread = 100.0;
+                Arena::new(room, room_config, rng)
+            })
+            .collect()
+    }
+
+    fn queue(
+        lobby: &mut Lobby,
+        name: &str,
+        room: Option<u8>,
+        arenas: &[Arena],
+        rng: &mut ThreadRng,
+    ) -> u64 {
+        lobby
+            .add(name, None, room, arenas, &NameRules::new(), rng)
+            .unwrap()
+    }
+
+    fn is_matched_in(lobby: &mut Lobby, ticket: u64, room: u8) -> bool {
+        match lobby.status(ticket) {
+            Ok(QueueStatus::Matched {
+                room: matched_room, ..
+            }) => matched_room == room,
+            _ => false,
+        }
+    }
+
+    fn is_waiting(lobby: &mut Lobby, ticket: u64) -> bool {
+        matches!(lobby.status(ticket), Ok(QueueStatus::Waiting { .. }))
+    }
+
+    #[test]
+    fn groups_the_closest_ratings() {
+        let mut rng = thread_rng();
+        let mut arenas = match_rooms(1, &mut rng);
+        // Alice beats Bob a couple of times, which leaves Carol and Dave (who haven't played yet)
+        // closest together
+        let alice = arenas[0]
+            .scoreboard
+            .key_for(&identity_of(None, "Alice"), &mut rng);
+        let bob = arenas[0]
+            .scoreboard
+            .key_for(&identity_of(None, "Bob"), &mut rng);
+        for _ in 0..2 {
+            arenas[0]
+                .scoreboard
+                .high_scores
+                .rate_kill(alice, bob, 200.0);
+        }
+        let mut lobby = Lobby::new();
+        let tickets: Vec<u64> = ["Alice", "Bob", "Carol", "Dave"]
+            .iter()
+            .map(|name| queue(&mut lobby, name, None, &arenas, &mut rng))
+            .collect();
+        lobby.update(Duration::from_millis(16), &mut arenas, &mut rng);
+        assert!(is_waiting(&mut lobby, tickets[0]));
+        assert!(is_waiting(&mut lobby, tickets[1]));
+        assert!(is_matched_in(&mut lobby, tickets[2], 0));
+        assert!(is_matched_in(&mut lobby, tickets[3], 0));
+        assert_eq!(arenas[0].reservation.as_ref().unwrap().tickets.len(), 2);
+    }
+
+    #[test]
+    fn times_out_without_a_match() {
+        let mut rng = thread_rng();
+        let mut arenas = match_rooms(1, &mut rng);
+        let mut lobby = Lobby::new();
+        // Short enough that Alice doesn't get forgotten for not checking in first
+        lobby.timeout = Duration::from_secs(2);
+        let ticket = queue(&mut lobby, "Alice", None, &arenas, &mut rng);
+        lobby.update(Duration::from_secs(1), &mut arenas, &mut rng);
+        assert!(is_waiting(&mut lobby, ticket));
+        lobby.update(Duration::from_secs(1), &mut arenas, &mut rng);
+        assert_eq!(lobby.status(ticket), Ok(QueueStatus::TimedOut));
+        // Players only hear how it went once
+        assert!(lobby.status(ticket).is_err());
+        assert!(arenas[0].reservation.is_none());
+    }
+
+    #[test]
+    fn only_matches_players_who_want_the_same_room() {
+        let mut rng = thread_rng();
+        let mut arenas = match_rooms(2, &mut rng);
+        let mut lobby = Lobby::new();
+        let alice = queue(&mut lobby, "Alice", Some(0), &arenas, &mut rng);
+        let bob = queue(&mut lobby, "Bob", Some(1), &arenas, &mut rng);
+        lobby.update(Duration::from_millis(16), &mut arenas, &mut rng);
+        assert!(is_waiting(&mut lobby, alice));
+        assert!(is_waiting(&mut lobby, bob));
+        let carol = queue(&mut lobby, "Carol", Some(1), &arenas, &mut rng);
+        lobby.update(Duration::from_millis(16), &mut arenas, &mut rng);
+        assert!(is_waiting(&mut lobby, alice));
+        assert!(is_matched_in(&mut lobby, bob, 1));
+        assert!(is_matched_in(&mut lobby, carol, 1));
+        assert!(arenas[0].reservation.is_none());
+        assert!(arenas[1].reservation.is_some());
+    }
+
+    #[test]
+    fn makes_up_colors_once_the_palette_runs_out() {
+        let mut color_picker = ColorPicker::new();
+        let palette_size = color_picker.colors.len();
+        let colors: Vec<Color> = (0..palette_size + 50)
+            .map(|_| color_picker.pop_color())
+            .collect();
+        assert!(colors.iter().all(is_valid_color));
+        // The made-up colors are all different from each other, too
+        for (i, color) in colors.iter().enumerate() {
+            assert!(colors[..i]
+                .iter()
+                .all(|other| color_distance(other, color) > 0.0));
+        }
+        // Colors given back get handed out again before any more are made up
+        color_picker.push_color(colors[0]);
+        assert_eq!(color_picker.pop_color(), colors[0]);
+    }
+
+    fn player_named(name: &str, game_settings: &GameSettings) -> HashMap<u8, PlayerState> {
+        let player_state = PlayerState::new(
+            game_settings,
+            1,
+            name.to_string(),
+            ColorPicker::new().pop_color(),
+            Vec2::zeros(),
+            0.05,
+            Weapon::from_kind(WeaponKind::RustySword),
+        );
+        vec![(1, player_state)].into_iter().collect()
+    }
+
+    #[test]
+    fn tidies_up_acceptable_names() {
+        let game_settings = GameSettings::new();
+        let name_rules = NameRules::new();
+        let players = player_named("Bob", &game_settings);
+        assert_eq!(
+            name_rules.check("  Alice   O'Hara ", &players, &game_settings),
+            Ok("Alice O'Hara".to_string())
+        );
+    }
+
+    #[test]
+    fn turns_down_names_that_break_the_rules() {
+        let game_settings = GameSettings {
+            min_name_length: 2,
+            max_name_length: 8,
+            ..GameSettings::new()
+        };
+        let mut name_rules = NameRules::new();
+        name_rules.blocklist.push(canonical_name("rude"));
+        let players = player_named("Bob", &game_settings);
+        for name in &[
+            // Too short and too long
+            "A",
+            "Bartholomew",
+            // Characters that aren't allowed
+            "Al!ce",
+            "Alice\u{202e}",
+            // No letters or numbers at all
+            "-_.'",
+            // Reserved, even dressed up
+            "Admin",
+            "4dm1n",
+            "N0-b0dy",
+            // Blocklisted anywhere in the name
+            "SoRude",
+            "r.u.d.e",
+            // Taken, or too much like a name that's taken
+            "Bob",
+            "bob",
+            "B0B",
+        ] {
+            assert!(
+                name_rules.check(name, &players, &game_settings).is_err(),
+                "{:?} should have been turned down",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn name_rules_can_be_changed() {
+        let game_settings = GameSettings::new();
+        let mut name_rules = NameRules::new();
+        name_rules.reserved = vec![canonical_name("Carol")];
+        name_rules.punctuation = "!".to_string();
+        let players = HashMap::new();
+        assert!(name_rules.check("Admin", &players, &game_settings).is_ok());
+        assert!(name_rules.check("Caro1", &players, &game_settings).is_err());
+        assert!(name_rules.check("Al!ce", &players, &game_settings).is_ok());
+        assert!(name_rules
+            .check("O'Hara", &players, &game_settings)
+            .is_err());
+    }
+
+    #[test]
+    fn lookalike_names_keep_their_own_scores() {
+        assert_eq!(identity_of(None, " ALICE "), identity_of(None, "alice"));
+        assert_ne!(identity_of(None, "Alice"), identity_of(None, "Aiice"));
+        assert_ne!(
+            identity_of(None, "Alice"),
+            identity_of(Some("Alice"), "Bob")
+        );
+    }
 }
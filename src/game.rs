@@ -1,18 +1,21 @@
 use crate::{
-    gfx::{ButtonState, ButtonValue, Color, Vec2},
+    gfx::{angle_facing, distance, ButtonState, ButtonValue, Color, Vec2},
     timer::Timer,
     VERSION,
 };
 
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::{hash_map::DefaultHasher, HashMap};
+use std::f32::consts::PI;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::time::Duration;
 
 /// Stateful, stack-based button processor.  You can use this to process button state/values and
-/// update a `PlayerInput` that you can send to the server.  Also handles the attack button.
+/// update a `PlayerInput` that you can send to the server.  Also handles the attack (`Action1`),
+/// block (`Action2`) and dodge (`Action3`) buttons.
 #[derive(Default)]
 pub struct ButtonProcessor {
     horizontal: Vec<ButtonValue>,
@@ -27,7 +30,8 @@ impl ButtonProcessor {
             vertical: Vec::new(),
         }
     }
-    /// Process one button, and update the PlayerInput accordingly. Handles movement & attack.
+    /// Process one button, and update the PlayerInput accordingly. Handles movement, attack, block
+    /// & dodge.
     pub fn process(
         &mut self,
         button_state: ButtonState,
@@ -39,6 +43,9 @@ impl ButtonProcessor {
                 ButtonValue::Up | ButtonValue::Down => self.vertical.push(button_value),
                 ButtonValue::Left | ButtonValue::Right => self.horizontal.push(button_value),
                 ButtonValue::Action1 => player_input.attack = true,
+                ButtonValue::Action2 => player_input.block = true,
+                // Holding the button down only dodges once (see `PlayerInput.dodge`)
+                ButtonValue::Action3 => player_input.dodge = true,
                 _ => (),
             },
             ButtonState::Released => match button_value {
@@ -47,6 +54,8 @@ impl ButtonProcessor {
                     self.horizontal.retain(|&x| x != button_value)
                 }
                 ButtonValue::Action1 => player_input.attack = false,
+                ButtonValue::Action2 => player_input.block = false,
+                ButtonValue::Action3 => player_input.dodge = false,
                 _ => (),
             },
         }
@@ -73,6 +82,21 @@ impl ButtonProcessor {
     }
 }
 
+// Whether `angle` is within an arc of `arc` radians centered on `facing`
+fn angle_within_arc(facing: f32, angle: f32, arc: f32) -> bool {
+    if arc >= 2.0 * PI {
+        return true;
+    }
+    // Normalize the difference into [-π, π]
+    let mut diff = (angle - facing) % (2.0 * PI);
+    if diff > PI {
+        diff -= 2.0 * PI;
+    } else if diff < -PI {
+        diff += 2.0 * PI;
+    }
+    diff.abs() <= arc * 0.5
+}
+
 /// Convenience trait that adds an `.f32()` method that returns a 32-bit float representation of
 /// something.  Implemented for `std::time::Duration` and `rusty_sword_arena::timer::Timer`.
 pub trait Floatable {
@@ -88,9 +112,86 @@ impl Floatable for Duration {
 /// Various game control actions. Used by the networking module and the server.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum GameControlMsg {
-    Join { name: String },
-    Leave { id: u8 },
-    Fetch,
+    ListRooms,
+    Join {
+        room: u8,
+        name: String,
+        weapon: Option<WeaponKind>,
+        identity: Option<String>,
+        color: Option<Color>,
+        skin: Option<u16>,
+        title: Option<String>,
+        join_ticket: Option<u64>,
+    },
+    Leave {
+        id: u8,
+        token: u64,
+    },
+    Resume {
+        token: u64,
+    },
+    Spectate {
+        room: u8,
+        name: String,
+        follow: Option<u8>,
+    },
+    Follow {
+        token: u64,
+        id: Option<u8>,
+    },
+    StopSpectating {
+        token: u64,
+    },
+    Chat {
+        id: u8,
+        token: u64,
+        text: String,
+        team_only: bool,
+    },
+    FetchTeamChat {
+        id: u8,
+        token: u64,
+    },
+    Fetch {
+        room: u8,
+    },
+    FetchScoreboard {
+        room: u8,
+    },
+    FetchScoreboardPage {
+        room: u8,
+        page: usize,
+        page_size: usize,
+    },
+    FetchRank {
+        room: u8,
+        key: u64,
+    },
+    Queue {
+        name: String,
+        identity: Option<String>,
+        room: Option<u8>,
+    },
+    FetchQueueStatus {
+        ticket: u64,
+    },
+    LeaveQueue {
+        ticket: u64,
+    },
+}
+
+/// Everything a client sends over the player input channel. Used by the networking module and the
+/// server.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PlayerInputMsg {
+    /// Input only counts if it comes with the resume token of the player it's for, so nobody else
+    /// can move someone around (or keep them from going AFK).
+    Input { input: PlayerInput, token: u64 },
+    /// "I'm still here!"  Only the player who joined knows their resume token, so nobody else can
+    /// keep them connected.
+    Heartbeat { id: u8, token: u64 },
+    /// Spectators don't have a player id, just a token
+    SpectatorHeartbeat { token: u64 },
 }
 
 /// The game settings.  Mostly useful if you want to try to write client-side animations that match
@@ -112,8 +213,113 @@ pub struct GameSettings {
     pub move_threshold: f32,
     /// Milliseconds. How long the server will wait to respawn a player who dies.
     pub respawn_delay: u64,
-    /// Milliseconds. How long the server will allow not receiving input before dropping a player.
+    /// Milliseconds. How long the server will go without a heartbeat from a player before deciding
+    /// their connection dropped.
     pub drop_delay: u64,
+    /// Milliseconds. How often clients send the server a heartbeat.  `ConnectionToServer` takes
+    /// care of this for you.
+    pub heartbeat_interval: u64,
+    /// Milliseconds. How long a player can go without moving, turning, attacking, blocking or
+    /// dodging before they're away from keyboard (AFK).  `0` means never.
+    pub afk_delay: u64,
+    /// Whether AFK players sit out (until they start playing again) instead of getting kicked.
+    /// Players sitting out give up their place: they don't count towards `max_players`, they're
+    /// listed in `GameState.spectators`, and they can only come back if there's room.
+    pub afk_spectate: bool,
+    /// The most spectators this server will allow.  Spectators don't count towards `max_players`.
+    pub max_spectators: u16,
+    /// The longest chat message (in characters) a player can send
+    pub max_chat_length: usize,
+    /// The most chat messages a player can send within `chat_rate_window`
+    pub chat_rate_limit: usize,
+    /// Milliseconds. See `chat_rate_limit`.
+    pub chat_rate_window: u64,
+    /// How many players the lobby puts together for a match in this room.  `0` means this isn't a
+    /// match room, so anyone can join it.  See `ConnectionToServer::queue()`.
+    pub match_size: u8,
+    /// How far apart players' ratings can be for the lobby to match them up.  The longer players
+    /// wait, the farther apart it lets them be.
+    pub match_rating_spread: f32,
+    /// How much stamina a player has when fully rested
+    pub max_stamina: f32,
+    /// How much stamina a player regains per second while not blocking
+    pub stamina_regen: f32,
+    /// How much stamina blocking a hit costs
+    pub block_cost: f32,
+    /// How much of a hit's damage gets through a block. `0.25` means a quarter.
+    pub block_damage_multiplier: f32,
+    /// How wide the block is, in radians, centered on the direction the player is facing.  Hits
+    /// from outside of this arc aren't blocked.
+    pub block_arc: f32,
+    /// Milliseconds. A block that starts this soon before a hit lands is a parry, which stops all
+    /// of the damage and knockback and doesn't cost any stamina.
+    pub parry_window: u64,
+    /// Milliseconds. How long an attacker who gets parried has to wait to attack again.
+    pub parry_penalty: u64,
+    /// How much stamina dodging costs
+    pub dodge_cost: f32,
+    /// How fast a dodge launches the player, in OpenGL units per second
+    pub dodge_speed: f32,
+    /// Milliseconds. How long a dodge lasts.  Nothing can hit a player while they are dodging.
+    pub dodge_duration: u64,
+    /// Milliseconds. How long a player can't be hurt after spawning.  Attacking ends it early.
+    pub spawn_protection: u64,
+    /// What kind of game the server is running
+    pub game_mode: GameMode,
+    /// Milliseconds. How long the warmup before each round lasts.  Not used in free-for-all.
+    pub warmup_delay: u64,
+    /// Milliseconds. How long the break after each round lasts.  Not used in free-for-all.
+    pub intermission_delay: u64,
+    /// The layout of the arena: its edges, obstacles, hazards and spawn points.
+    pub map: ArenaMap,
+    /// How many teams players get split into.  `0` means no teams -- everyone for themselves.
+    pub teams: u8,
+    /// Whether players can hurt their own teammates.  Doesn't matter if there aren't any teams.
+    pub friendly_fire: bool,
+    /// Milliseconds. How long you have to hold a hill by yourself to earn a point.
+    pub hill_point_delay: u64,
+    /// How fast someone carrying a flag can move.  `0.75` means 75% of the usual max velocity.
+    pub flag_carry_speed_multiplier: f32,
+    /// Milliseconds. How long a dropped flag lies on the ground before it goes home by itself.
+    pub flag_return_delay: u64,
+    /// How the safe zone shrinks, one phase at a time.  Empty means there's no safe zone -- just
+    /// the edge of the arena.
+    pub zone_phases: Vec<ZonePhase>,
+    /// The most pickups that can be lying around the arena at once.  `0` means no pickups.
+    pub max_pickups: u8,
+    /// Milliseconds. How often a new pickup shows up (if there's room for one).
+    pub pickup_spawn_delay: u64,
+    /// Milliseconds. How long a buff from a pickup lasts.
+    pub buff_duration: u64,
+    /// How much health a health potion gives back
+    pub potion_heal: f32,
+    /// How much faster a speed boost lets you move.  `1.5` means 150% of the usual max velocity.
+    pub speed_boost_multiplier: f32,
+    /// How much more damage a damage buff makes your attacks do.  `1.5` means 150%.
+    pub damage_buff_multiplier: f32,
+    /// How much damage gets through a shield.  `0.5` means half.
+    pub shield_damage_multiplier: f32,
+    /// Milliseconds. Anyone who hurt a player this recently before they died gets an assist.
+    pub assist_window: u64,
+    /// Milliseconds. How long a kill stays in the kill feed.
+    pub kill_feed_duration: u64,
+    /// The server keeps high scores from one run to the next.  Every so often the scores get
+    /// reset and a new season starts.  The first season is `1`.
+    pub season: u32,
+    /// The most a player's rating can go up (or down) from a single kill.  See `Score::rating()`.
+    pub rating_k_factor: f32,
+    /// How many skins players can pick from.  Skins are numbered from `0`, and it's up to your
+    /// client to decide what each one looks like.
+    pub skins: u16,
+    /// The longest title (in characters) a player can give themselves
+    pub max_title_length: usize,
+    /// The shortest name (in characters) a player can have
+    pub min_name_length: usize,
+    /// The longest name (in characters) a player can have
+    pub max_name_length: usize,
+    /// Milliseconds. How long the server hangs on to a player whose connection dropped, so they
+    /// can pick up where they left off if they reconnect.
+    pub resume_grace_period: u64,
 }
 
 impl GameSettings {
@@ -128,6 +334,52 @@ impl GameSettings {
             move_threshold: 0.05,
             respawn_delay: 5000,
             drop_delay: 4000,
+            heartbeat_interval: 1000,
+            afk_delay: 60000,
+            afk_spectate: false,
+            max_spectators: 32,
+            max_chat_length: 200,
+            chat_rate_limit: 5,
+            chat_rate_window: 10000,
+            match_size: 0,
+            match_rating_spread: 200.0,
+            max_stamina: 100.0,
+            stamina_regen: 25.0,
+            block_cost: 30.0,
+            block_damage_multiplier: 0.25,
+            block_arc: PI,
+            parry_window: 200,
+            parry_penalty: 1500,
+            dodge_cost: 35.0,
+            dodge_speed: 1.2,
+            dodge_duration: 250,
+            spawn_protection: 2000,
+            game_mode: GameMode::FreeForAll,
+            warmup_delay: 10000,
+            intermission_delay: 10000,
+            map: ArenaMap::new(),
+            teams: 0,
+            friendly_fire: false,
+            hill_point_delay: 1000,
+            flag_carry_speed_multiplier: 0.75,
+            flag_return_delay: 15000,
+            zone_phases: Vec::new(),
+            max_pickups: 3,
+            pickup_spawn_delay: 10000,
+            buff_duration: 10000,
+            potion_heal: 50.0,
+            speed_boost_multiplier: 1.5,
+            damage_buff_multiplier: 1.5,
+            shield_damage_multiplier: 0.5,
+            assist_window: 5000,
+            kill_feed_duration: 5000,
+            season: 1,
+            rating_k_factor: 32.0,
+            skins: 16,
+            max_title_length: 24,
+            min_name_length: 1,
+            max_name_length: 20,
+            resume_grace_period: 30000,
         }
     }
     /// Looking forward to the day when game settings can be changed mid-game.
@@ -147,6 +399,60 @@ impl Hash for GameSettings {
         (self.move_threshold as u32).hash(state);
         self.respawn_delay.hash(state);
         self.drop_delay.hash(state);
+        self.heartbeat_interval.hash(state);
+        self.afk_delay.hash(state);
+        self.afk_spectate.hash(state);
+        self.max_spectators.hash(state);
+        self.max_chat_length.hash(state);
+        self.chat_rate_limit.hash(state);
+        self.chat_rate_window.hash(state);
+        self.match_size.hash(state);
+        // Truncating a setting with a fraction (like a 0.25 multiplier) to an integer would lose
+        // most of it, so settings with fractions hash all of their bits
+        self.match_rating_spread.to_bits().hash(state);
+        self.max_stamina.to_bits().hash(state);
+        self.stamina_regen.to_bits().hash(state);
+        self.block_cost.to_bits().hash(state);
+        self.parry_window.hash(state);
+        self.parry_penalty.hash(state);
+        self.dodge_cost.to_bits().hash(state);
+        self.dodge_duration.hash(state);
+        self.block_damage_multiplier.to_bits().hash(state);
+        self.block_arc.to_bits().hash(state);
+        self.dodge_speed.to_bits().hash(state);
+        self.spawn_protection.hash(state);
+        self.game_mode.hash(state);
+        self.warmup_delay.hash(state);
+        self.intermission_delay.hash(state);
+        // Everything about the map counts, not just its name, so an edited map hashes differently
+        bincode::serialize(&self.map).unwrap().hash(state);
+        self.teams.hash(state);
+        self.friendly_fire.hash(state);
+        self.hill_point_delay.hash(state);
+        self.flag_carry_speed_multiplier.to_bits().hash(state);
+        self.flag_return_delay.hash(state);
+        for phase in self.zone_phases.iter() {
+            phase.wait.hash(state);
+            phase.shrink.hash(state);
+            phase.radius.to_bits().hash(state);
+            phase.damage_per_second.to_bits().hash(state);
+        }
+        self.max_pickups.hash(state);
+        self.pickup_spawn_delay.hash(state);
+        self.buff_duration.hash(state);
+        self.potion_heal.to_bits().hash(state);
+        self.speed_boost_multiplier.to_bits().hash(state);
+        self.damage_buff_multiplier.to_bits().hash(state);
+        self.shield_damage_multiplier.to_bits().hash(state);
+        self.assist_window.hash(state);
+        self.kill_feed_duration.hash(state);
+        self.season.hash(state);
+        self.rating_k_factor.to_bits().hash(state);
+        self.skins.hash(state);
+        self.max_title_length.hash(state);
+        self.min_name_length.hash(state);
+        self.max_name_length.hash(state);
+        self.resume_grace_period.hash(state);
     }
 }
 
@@ -156,26 +462,532 @@ impl Default for GameSettings {
     }
 }
 
-/// A single player's score
+// The point on the line segment from `a` to `b` which is closest to `pos`
+fn closest_point_on_segment(a: &Vec2, b: &Vec2, pos: &Vec2) -> Vec2 {
+    let segment = b - a;
+    let length_squared = segment.dot(&segment);
+    // How far along the segment the closest point is, clamped to the segment itself
+    let t = if length_squared > 0.0 {
+        ((pos - a).dot(&segment) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    a + (segment * t)
+}
+
+// Where the path from `from` to `to` crosses the edge of a circle, as fractions of the way along
+// the path (`0.0` at `from`, `1.0` at `to`), nearest first
+fn circle_crossings(from: &Vec2, to: &Vec2, center: &Vec2, radius: f32) -> Vec<f32> {
+    let path = to - from;
+    let offset = from - center;
+    let a = path.dot(&path);
+    if a == 0.0 {
+        return Vec::new();
+    }
+    let b = 2.0 * offset.dot(&path);
+    let c = offset.dot(&offset) - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+    let root = discriminant.sqrt();
+    vec![(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)]
+        .into_iter()
+        .filter(|t| (0.0..=1.0).contains(t))
+        .collect()
+}
+
+// Where the path from `from` to `to` crosses the line segment from `a` to `b`, as a fraction of the
+// way along the path
+fn segment_crossing(from: &Vec2, to: &Vec2, a: &Vec2, b: &Vec2) -> Option<f32> {
+    let path = to - from;
+    let edge = b - a;
+    let denominator = path.x * edge.y - path.y * edge.x;
+    // Parallel lines never cross
+    if denominator == 0.0 {
+        return None;
+    }
+    let offset = a - from;
+    let t = (offset.x * edge.y - offset.y * edge.x) / denominator;
+    let u = (offset.x * path.y - offset.y * path.x) / denominator;
+    if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// An area of the arena, in OpenGL units.  Used for the edge of the arena, obstacles and hazards.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Region {
+    /// A circle
+    Circle { center: Vec2, radius: f32 },
+    /// A polygon made from these points, in order.  The last point connects back to the first.
+    Polygon { points: Vec<Vec2> },
+}
+
+impl Region {
+    /// A square centered on the origin that reaches out `half_width` in each direction.
+    pub fn square(half_width: f32) -> Self {
+        Region::Polygon {
+            points: vec![
+                Vec2::new(-half_width, -half_width),
+                Vec2::new(half_width, -half_width),
+                Vec2::new(half_width, half_width),
+                Vec2::new(-half_width, half_width),
+            ],
+        }
+    }
+    /// Whether `pos` is inside the region
+    pub fn contains(&self, pos: &Vec2) -> bool {
+        match self {
+            Region::Circle { center, radius } => distance(center, pos) <= *radius,
+            Region::Polygon { points } => {
+                if points.is_empty() {
+                    return false;
+                }
+                // Count how many edges a line going right from `pos` crosses.  Odd means inside.
+                let mut inside = false;
+                let mut previous = points[points.len() - 1];
+                for point in points {
+                    if (point.y > pos.y) != (previous.y > pos.y)
+                        && pos.x
+                            < (previous.x - point.x) * (pos.y - point.y) / (previous.y - point.y)
+                                + point.x
+                    {
+                        inside = !inside;
+                    }
+                    previous = *point;
+                }
+                inside
+            }
+        }
+    }
+    /// The first place the path from `from` to `to` crosses the edge of the region, as a fraction
+    /// of the way along the path (`0.0` at `from`, `1.0` at `to`).  `None` if it never does.
+    pub fn crossing(&self, from: &Vec2, to: &Vec2) -> Option<f32> {
+        match self {
+            Region::Circle { center, radius } => circle_crossings(from, to, center, *radius)
+                .into_iter()
+                .next(),
+            Region::Polygon { points } => {
+                let mut first: Option<f32> = None;
+                let mut previous = *points.last()?;
+                for point in points {
+                    if let Some(t) = segment_crossing(from, to, &previous, point) {
+                        first = Some(first.unwrap_or(t).min(t));
+                    }
+                    previous = *point;
+                }
+                first
+            }
+        }
+    }
+    /// If a circle at `pos` with `radius` overlaps the region, returns how far the circle needs to
+    /// move so that it doesn't anymore.
+    pub fn push_out(&self, pos: &Vec2, radius: f32) -> Option<Vec2> {
+        match self {
+            Region::Circle {
+                center,
+                radius: region_radius,
+            } => {
+                let gap = distance(center, pos);
+                let overlap = region_radius + radius - gap;
+                if overlap <= 0.0 {
+                    return None;
+                }
+                // If we're exactly in the middle, any direction will do
+                let normal = if gap > 0.0 {
+                    (pos - center).normalize()
+                } else {
+                    Vec2::new(1.0, 0.0)
+                };
+                Some(normal * overlap)
+            }
+            Region::Polygon { points } => {
+                // Find the closest point on any edge of the polygon
+                let mut closest: Option<Vec2> = None;
+                let mut previous = *points.last()?;
+                for point in points {
+                    let candidate = closest_point_on_segment(&previous, point, pos);
+                    closest = match closest {
+                        Some(c) if distance(&c, pos) <= distance(&candidate, pos) => Some(c),
+                        _ => Some(candidate),
+                    };
+                    previous = *point;
+                }
+                let closest = closest?;
+                let gap = distance(&closest, pos);
+                if gap <= 0.0 {
+                    return None;
+                }
+                if self.contains(pos) {
+                    // All the way inside, so go out through the nearest edge
+                    Some((closest - pos).normalize() * (gap + radius))
+                } else if gap < radius {
+                    Some((pos - closest).normalize() * (radius - gap))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+    /// Whether the region has any room inside it: a circle needs a radius, and a polygon needs at
+    /// least 3 points that don't all fall on one line.  Returns an `Err(String)` saying what's
+    /// wrong if it doesn't.
+    pub fn check(&self) -> Result<(), String> {
+        let finite = |point: &Vec2| point.x.is_finite() && point.y.is_finite();
+        match self {
+            Region::Circle { center, radius } => {
+                if !finite(center) || !radius.is_finite() || *radius <= 0.0 {
+                    return Err(format!(
+                        "a circle at ({}, {}) with radius {} is empty",
+                        center.x, center.y, radius
+                    ));
+                }
+            }
+            Region::Polygon { points } => {
+                if points.len() < 3 || !points.iter().all(finite) {
+                    return Err(format!(
+                        "a polygon needs at least 3 points, not {:?}",
+                        points
+                    ));
+                }
+                let (min, max) = self.bounds();
+                if min.x >= max.x || min.y >= max.y {
+                    return Err(format!(
+                        "the points of a polygon are all in a line {:?}",
+                        points
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// The smallest rectangle containing the entire region, as (bottom left, top right) corners
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        match self {
+            Region::Circle { center, radius } => (
+                Vec2::new(center.x - radius, center.y - radius),
+                Vec2::new(center.x + radius, center.y + radius),
+            ),
+            Region::Polygon { points } => {
+                let mut min = Vec2::new(f32::MAX, f32::MAX);
+                let mut max = Vec2::new(f32::MIN, f32::MIN);
+                for point in points {
+                    min.x = min.x.min(point.x);
+                    min.y = min.y.min(point.y);
+                    max.x = max.x.max(point.x);
+                    max.y = max.y.max(point.y);
+                }
+                (min, max)
+            }
+        }
+    }
+}
+
+/// A part of the arena that hurts to stand in.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hazard {
+    /// Where the hazard is
+    pub region: Region,
+    /// How much damage a player standing in the hazard takes each second
+    pub damage_per_second: f32,
+}
+
+/// The layout of the arena.  The server either loads a map from a file or uses the default (a
+/// plain, empty square), and sends it to clients inside of `GameSettings` so they can draw it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ArenaMap {
+    /// The name of the map
+    pub name: String,
+    /// The edge of the arena.  Leave it, and you are likely to be eaten by a grue.
+    pub boundary: Region,
+    /// Solid obstacles that players can't walk through and projectiles can't fly through
+    pub obstacles: Vec<Region>,
+    /// Areas that hurt to stand in
+    pub hazards: Vec<Hazard>,
+    /// Where players spawn.  If there aren't any, players spawn at random places in the arena.
+    pub spawn_points: Vec<Vec2>,
+    /// Where pickups show up.  If there aren't any, pickups show up at random places in the arena.
+    #[serde(default)]
+    pub pickup_points: Vec<Vec2>,
+    /// The hills to hold in king-of-the-hill games
+    #[serde(default)]
+    pub hills: Vec<Region>,
+    /// Where each team's flag lives in capture-the-flag games, in order of team ID
+    #[serde(default)]
+    pub flag_bases: Vec<Vec2>,
+}
+
+impl ArenaMap {
+    /// Create the default map: a plain square from -1.0 to 1.0, with nothing in it.
+    pub fn new() -> Self {
+        Self {
+            name: "Classic".to_string(),
+            boundary: Region::square(1.0),
+            obstacles: Vec::new(),
+            hazards: Vec::new(),
+            spawn_points: Vec::new(),
+            pickup_points: Vec::new(),
+            hills: vec![Region::Circle {
+                center: Vec2::zeros(),
+                radius: 0.2,
+            }],
+            flag_bases: vec![Vec2::new(-0.8, 0.0), Vec2::new(0.8, 0.0)],
+        }
+    }
+    /// Used by the server to make sure a map it loads can be played on.  Every region needs room
+    /// inside it, and spawn points, pickup points and flag bases need to be somewhere a player
+    /// could stand.  Returns an `Err(String)` saying what's wrong if the map won't work.
+    pub fn check(&self) -> Result<(), String> {
+        self.boundary
+            .check()
+            .map_err(|err| format!("The boundary is no good: {}", err))?;
+        let regions = self
+            .obstacles
+            .iter()
+            .chain(self.hazards.iter().map(|hazard| &hazard.region))
+            .chain(self.hills.iter());
+        for region in regions {
+            region.check()?;
+        }
+        let points = [
+            ("spawn point", &self.spawn_points),
+            ("pickup point", &self.pickup_points),
+            ("flag base", &self.flag_bases),
+        ];
+        for (kind, points) in points.iter() {
+            if let Some(point) = points.iter().find(|point| !self.is_open(point)) {
+                return Err(format!(
+                    "The {} at ({}, {}) is outside the arena or inside an obstacle.",
+                    kind, point.x, point.y
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// Whether `pos` is somewhere a player could stand: inside the boundary and not inside an
+    /// obstacle.
+    pub fn is_open(&self, pos: &Vec2) -> bool {
+        self.boundary.contains(pos) && !self.obstacles.iter().any(|x| x.contains(pos))
+    }
+    /// How far along the path from `from` to `to` (`0.0` at `from`, `1.0` at `to`) something solid
+    /// gets in the way: an obstacle, or the edge of the arena.  `None` if the whole path is open.
+    pub fn blocked_at(&self, from: &Vec2, to: &Vec2) -> Option<f32> {
+        if !self.is_open(from) {
+            return Some(0.0);
+        }
+        std::iter::once(&self.boundary)
+            .chain(self.obstacles.iter())
+            .filter_map(|region| region.crossing(from, to))
+            .min_by(|a, b| a.partial_cmp(b).unwrap())
+    }
+    /// Whether `pos` is open and not in a hazard either
+    pub fn is_safe(&self, pos: &Vec2) -> bool {
+        self.is_open(pos) && !self.hazards.iter().any(|x| x.region.contains(pos))
+    }
+    /// Used by the server to pick a place to spawn a player.  Picks one of the spawn points, or a
+    /// random safe place away from the edges if the map doesn't have any spawn points.  If there's
+    /// a safe zone, only places inside of it will do.
+    pub fn random_spawn<R: Rng>(&self, rng: &mut R, zone: Option<&SafeZone>) -> Vec2 {
+        let spawn_points = self.spawn_points_in(zone);
+        if !spawn_points.is_empty() {
+            return spawn_points[rng.gen_range(0, spawn_points.len())];
+        }
+        match zone {
+            Some(zone) => self.random_safe_pos_in(rng, zone),
+            None => self.random_safe_pos(rng),
+        }
+    }
+    // The spawn points that are inside the zone, if there is one
+    fn spawn_points_in(&self, zone: Option<&SafeZone>) -> Vec<Vec2> {
+        self.spawn_points
+            .iter()
+            .filter(|point| match zone {
+                Some(zone) => zone.contains(point),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+    // A random safe place inside the zone
+    fn random_safe_pos_in<R: Rng>(&self, rng: &mut R, zone: &SafeZone) -> Vec2 {
+        // Stay away from the very edge of the zone
+        let reach = zone.radius * 0.9;
+        for _ in 0..100 {
+            let angle = rng.gen::<f32>() * 2.0 * PI;
+            let pos = zone.center + Vec2::new(angle.cos(), angle.sin()) * rng.gen::<f32>() * reach;
+            if self.is_safe(&pos) {
+                return pos;
+            }
+        }
+        // Well, we tried
+        zone.center
+    }
+    /// A random safe place in the arena, away from the edges
+    pub fn random_safe_pos<R: Rng>(&self, rng: &mut R) -> Vec2 {
+        let (min, max) = self.boundary.bounds();
+        let center = (min + max) * 0.5;
+        // Stay away from the very edges of the arena
+        let reach = (max - min) * 0.5 * 0.9;
+        for _ in 0..100 {
+            let pos = Vec2::new(
+                center.x + rng.gen_range(-reach.x, reach.x),
+                center.y + rng.gen_range(-reach.y, reach.y),
+            );
+            if self.is_safe(&pos) {
+                return pos;
+            }
+        }
+        // Well, we tried
+        center
+    }
+    /// Used by the server to pick a place to spawn a player that is as far away from `enemies` as
+    /// possible.  Considers all of the spawn points, or a handful of random places if the map
+    /// doesn't have any spawn points.
+    pub fn safest_spawn<R: Rng>(
+        &self,
+        rng: &mut R,
+        enemies: &[Vec2],
+        zone: Option<&SafeZone>,
+    ) -> Vec2 {
+        if enemies.is_empty() {
+            return self.random_spawn(rng, zone);
+        }
+        let spawn_points = self.spawn_points_in(zone);
+        let candidates = if spawn_points.is_empty() {
+            (0..16).map(|_| self.random_spawn(rng, zone)).collect()
+        } else {
+            spawn_points
+        };
+        let closest_enemy = |pos: &Vec2| {
+            enemies
+                .iter()
+                .map(|enemy| distance(pos, enemy))
+                .fold(f32::MAX, f32::min)
+        };
+        let mut best = candidates[0];
+        let mut best_distance = closest_enemy(&best);
+        for candidate in candidates.into_iter().skip(1) {
+            let candidate_distance = closest_enemy(&candidate);
+            if candidate_distance > best_distance {
+                best = candidate;
+                best_distance = candidate_distance;
+            }
+        }
+        best
+    }
+}
+
+impl Default for ArenaMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything the server keeps track of about how a player is doing, beyond their points
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Stats {
+    /// How many players this player has killed (teammates don't count)
+    pub kills: u32,
+    /// How many times this player has died
+    pub deaths: u32,
+    /// How many of those deaths weren't another player's doing (grues, hazards, the dark)
+    pub suicides: u32,
+    /// How much damage this player has done to other players
+    pub damage_dealt: f32,
+    /// How much damage this player has taken, from anything
+    pub damage_taken: f32,
+    /// How many attacks hit somebody
+    pub hits: u32,
+    /// How many attacks didn't hit anybody
+    pub misses: u32,
+    /// How many kills this player has made since they last died
+    pub streak: u32,
+    /// The most kills this player has ever made without dying
+    pub longest_streak: u32,
+    /// How long this player has spent alive, all together
+    pub time_alive: Duration,
+}
+
+impl Stats {
+    /// Kills per death.  If you haven't died yet, it's just your kills.
+    pub fn kill_death_ratio(&self) -> f32 {
+        self.kills as f32 / self.deaths.max(1) as f32
+    }
+    /// The fraction of attacks that hit somebody [0.0, 1.0]
+    pub fn accuracy(&self) -> f32 {
+        let attacks = self.hits + self.misses;
+        if attacks == 0 {
+            0.0
+        } else {
+            self.hits as f32 / attacks as f32
+        }
+    }
+}
+
+/// The rating every player starts out with.  See `Score::rating()`.
+pub const STARTING_RATING: f32 = 1500.0;
+
+/// A single player's score
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Score {
+    key: u64,
     name: String,
     points: i32,
+    rating: f32,
+    stats: Stats,
 }
 
 impl Score {
     /// Create a new score
-    fn new(name: &str, points: i32) -> Self {
+    fn new(key: u64, name: &str, points: i32) -> Self {
         Self {
+            key,
             name: name.to_string(),
             points,
+            rating: STARTING_RATING,
+            stats: Stats::default(),
         }
     }
+    /// The key of the player this score belongs to.  Scores are kept by key, so they stick with a
+    /// player even if they change their name.  See `PlayerState.key`.
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+    /// The name of the player this score belongs to (the name they used most recently)
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// How many points the player has
+    pub fn points(&self) -> i32 {
+        self.points
+    }
+    /// How good the player is.  Points go to whoever plays the longest, but a rating goes up when
+    /// you kill someone and down when someone kills you -- by more when the other player is rated
+    /// higher than you, and by less when they're rated lower.  Everyone starts at
+    /// `STARTING_RATING`.
+    pub fn rating(&self) -> f32 {
+        self.rating
+    }
+    /// The rest of the player's stats
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
 }
 
 impl fmt::Display for Score {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:<4.0} {}", self.points, self.name)
+        write!(f, "{:<4.0} {} ({:.0})", self.points, self.name, self.rating)
+    }
+}
+
+// Scores are the same score if they're for the same player with the same points, so equality
+// agrees with the ordering below
+impl PartialEq for Score {
+    fn eq(&self, other: &Score) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -189,7 +1001,8 @@ impl Ord for Score {
             if self.name < other.name {
                 Ordering::Greater
             } else if self.name == other.name {
-                Ordering::Equal
+                // Two players can have the same name, but never the same key
+                other.key.cmp(&self.key)
             } else {
                 Ordering::Less
             }
@@ -205,8 +1018,21 @@ impl PartialOrd for Score {
     }
 }
 
-/// High Scores!  High scores are reset every time the server restarts, but other than that they
-/// are persistent across joins/leaves.
+/// The different ways to sort `HighScores`.  Every sort puts the best first.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ScoreSort {
+    Points,
+    Kills,
+    KillDeathRatio,
+    DamageDealt,
+    Accuracy,
+    LongestStreak,
+    TimeAlive,
+    Rating,
+}
+
+/// High Scores!  High scores are persistent across joins/leaves, and the server saves them so they
+/// last until the next season starts.
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub struct HighScores {
     pub scores: Vec<Score>,
@@ -219,20 +1045,16 @@ impl HighScores {
             scores: Vec::<Score>::new(),
         }
     }
-    /// Bump a player's score up by one. If the player is not present, he will be added.
-    pub fn score(&mut self, name: &str) {
-        self.add_player(name);
-        if let Some(score) = self.scores.iter_mut().find(|x| x.name == name) {
-            score.points += 1;
-        }
+    /// Bump the score of the player with this key up by one. If the player is not present, he will
+    /// be added.
+    pub fn score(&mut self, key: u64) {
+        self.score_mut(key).points += 1;
         self.sort();
     }
-    /// Decrement a player's score by one. If the player is not present, he will be added.
-    pub fn penalize(&mut self, name: &str) {
-        self.add_player(name);
-        if let Some(score) = self.scores.iter_mut().find(|x| x.name == name) {
-            score.points -= 1;
-        }
+    /// Decrement the score of the player with this key by one. If the player is not present, he
+    /// will be added.
+    pub fn penalize(&mut self, key: u64) {
+        self.score_mut(key).points -= 1;
         self.sort();
     }
     /// Return a clone with only the top-10 scoring players. This is what the server sends the
@@ -244,21 +1066,145 @@ impl HighScores {
         }
         top10
     }
-    /// Add a new player with a zero score. Used internally by both `score()` and `penalize()`
-    pub fn add_player(&mut self, name: &str) {
-        // Abort if we've already seen this player.
-        if self.scores.iter().any(|x| x.name == name) {
-            return;
+    /// All of the scores, sorted some other way than by points.  Ties are sorted the same way as
+    /// usual.
+    pub fn sorted_by(&self, sort: ScoreSort) -> Vec<&Score> {
+        let mut scores: Vec<&Score> = self.scores.iter().collect();
+        let key = |score: &Score| -> f32 {
+            let stats = &score.stats;
+            match sort {
+                ScoreSort::Points => score.points as f32,
+                ScoreSort::Kills => stats.kills as f32,
+                ScoreSort::KillDeathRatio => stats.kill_death_ratio(),
+                ScoreSort::DamageDealt => stats.damage_dealt,
+                ScoreSort::Accuracy => stats.accuracy(),
+                ScoreSort::LongestStreak => stats.longest_streak as f32,
+                ScoreSort::TimeAlive => stats.time_alive.as_millis() as f32,
+                ScoreSort::Rating => score.rating,
+            }
+        };
+        // The scores are already sorted by points, and a stable sort keeps it that way for ties
+        scores.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap_or(Ordering::Equal));
+        scores
+    }
+    /// Used by the server when one player kills another.  This works like Elo: the killer's rating
+    /// goes up by however much the victim's goes down, which is at most `k_factor`.
+    pub fn rate_kill(&mut self, killer: u64, victim: u64, k_factor: f32) {
+        let killer_rating = self.rating(killer);
+        let victim_rating = self.rating(victim);
+        // How likely the killer was to win, going by the ratings
+        let expected = 1.0 / (1.0 + 10f32.powf((victim_rating - killer_rating) / 400.0));
+        let change = k_factor * (1.0 - expected);
+        self.score_mut(killer).rating += change;
+        self.score_mut(victim).rating -= change;
+    }
+    /// The rating of the player with this key, or `STARTING_RATING` if there's no such player.
+    pub fn rating(&self, key: u64) -> f32 {
+        self.scores
+            .iter()
+            .find(|x| x.key == key)
+            .map_or(STARTING_RATING, |score| score.rating)
+    }
+    /// Used by the server so that everyone's rating shows up next to their round score.
+    pub fn copy_ratings(&mut self, other: &HighScores) {
+        for score in self.scores.iter_mut() {
+            score.rating = other.rating(score.key);
         }
-        self.scores.push(Score::new(name, 0));
+    }
+    /// Where the player with this key stands.  `1` is first place.  `None` if there's no such
+    /// player.
+    pub fn rank(&self, key: u64) -> Option<usize> {
+        self.scores
+            .iter()
+            .position(|x| x.key == key)
+            .map(|index| index + 1)
+    }
+    /// The score of the player with this key, along with their rank.  `None` if there's no such
+    /// player.
+    pub fn ranked_score(&self, key: u64) -> Option<RankedScore> {
+        let rank = self.rank(key)?;
+        Some(RankedScore {
+            rank,
+            score: self.scores[rank - 1].clone(),
+        })
+    }
+    /// One page of the scores, `page_size` scores long.  The first page is `0`.
+    pub fn page(&self, page: usize, page_size: usize) -> ScoreboardPage {
+        let page_size = page_size.max(1);
+        let start = page.saturating_mul(page_size).min(self.scores.len());
+        let end = start.saturating_add(page_size).min(self.scores.len());
+        ScoreboardPage {
+            page,
+            pages: self.scores.len().div_ceil(page_size),
+            total: self.scores.len(),
+            scores: self.scores[start..end]
+                .iter()
+                .enumerate()
+                .map(|(i, score)| RankedScore {
+                    rank: start + i + 1,
+                    score: score.clone(),
+                })
+                .collect(),
+        }
+    }
+    /// Used by the server to update the stats of the player with this key. If the player is not
+    /// present, he will be added.
+    pub fn stats_mut(&mut self, key: u64) -> &mut Stats {
+        &mut self.score_mut(key).stats
+    }
+    /// Add a new player with a zero score.  If we've already seen a player with this key, we just
+    /// remember their new name.
+    pub fn add_player(&mut self, key: u64, name: &str) {
+        self.score_mut(key).name = name.to_string();
         self.sort();
     }
+    /// Find the score for this key, adding one (with no name yet) if there isn't one
+    fn score_mut(&mut self, key: u64) -> &mut Score {
+        let index = match self.scores.iter().position(|x| x.key == key) {
+            Some(index) => index,
+            None => {
+                self.scores.push(Score::new(key, "", 0));
+                self.scores.len() - 1
+            }
+        };
+        &mut self.scores[index]
+    }
     // Sort the internal score vector in the direction we want.
     fn sort(&mut self) {
         self.scores.sort_by(|a, b| b.cmp(a));
     }
 }
 
+/// A score, along with where it stands.  `1` is first place.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RankedScore {
+    pub rank: usize,
+    pub score: Score,
+}
+
+/// One page of the scoreboard.  See `ConnectionToServer::get_scoreboard_page()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ScoreboardPage {
+    /// Which page this is.  The first page is `0`.
+    pub page: usize,
+    /// How many pages there are in all
+    pub pages: usize,
+    /// How many scores there are in all
+    pub total: usize,
+    /// The scores on this page, best first.  Empty if you asked for a page past the end.
+    pub scores: Vec<RankedScore>,
+}
+
+impl fmt::Display for ScoreboardPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Page {} of {}", self.page + 1, self.pages)?;
+        for ranked in self.scores.iter() {
+            write!(f, "\n{:>4}. {}", ranked.rank, ranked.score)?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Display for HighScores {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let _ = write!(f, "-----------\nHigh Scores\n-----------");
@@ -269,6 +1215,457 @@ impl fmt::Display for HighScores {
     }
 }
 
+/// The different kinds of games the server can run.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum GameMode {
+    /// One endless round where everyone fights everyone.  The classic.
+    FreeForAll,
+    /// Rounds last `duration` milliseconds, and whoever has the most points at the end wins.
+    TimedDeathmatch { duration: u64 },
+    /// The first player to get `points` points wins the round.
+    FirstTo { points: i32 },
+    /// Nobody respawns until the round is over.  The last player alive wins.
+    LastPlayerStanding,
+    /// Be the only living player on a hill to earn a point every `GameSettings.hill_point_delay`.
+    /// The first player to get `points` points wins the round.  Kills don't count towards the
+    /// round.
+    KingOfTheHill { points: i32 },
+    /// Teams steal each other's flags and carry them home.  The first team to make `captures`
+    /// captures wins the round.  Kills don't count towards the round.
+    CaptureTheFlag { captures: i32 },
+}
+
+impl GameMode {
+    /// Whether the game is divided up into rounds
+    pub fn has_rounds(&self) -> bool {
+        *self != GameMode::FreeForAll
+    }
+    /// Whether points come from objectives (hills and flags) instead of from kills
+    pub fn has_objectives(&self) -> bool {
+        matches!(
+            self,
+            GameMode::KingOfTheHill { .. } | GameMode::CaptureTheFlag { .. }
+        )
+    }
+}
+
+/// Where the current round is in its lifecycle.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RoundPhase {
+    /// Getting ready for the round to start.  You can fight, but it doesn't count.
+    Warmup,
+    /// The round is on!
+    Active,
+    /// The round is over.  Check out the scores, and get ready for the next one.
+    Intermission,
+}
+
+/// The state of the current round.  Free-for-all games have one round that never ends.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Round {
+    /// Which round this is, starting at 1
+    pub number: u32,
+    /// Where the round is in its lifecycle
+    pub phase: RoundPhase,
+    /// Counts down to the end of the current phase.  `None` means the phase only ends when
+    /// somebody wins.
+    pub phase_timer: Option<Timer>,
+    /// Just the points scored during this round.  Points scored during the warmup and
+    /// intermission don't count towards the round (but they still count towards `HighScores`).
+    pub scores: HighScores,
+    /// The name of whoever won the previous round, if anyone did
+    pub winner: Option<String>,
+}
+
+impl Round {
+    /// Used by the server to set up the first round
+    pub fn new(game_settings: &GameSettings) -> Self {
+        let (phase, phase_timer) = if game_settings.game_mode.has_rounds() {
+            (
+                RoundPhase::Warmup,
+                Some(Timer::from_millis(game_settings.warmup_delay)),
+            )
+        } else {
+            (RoundPhase::Active, None)
+        };
+        Self {
+            number: 1,
+            phase,
+            phase_timer,
+            scores: HighScores::new(),
+            winner: None,
+        }
+    }
+    /// Used by the server. Counts down the phase timer.
+    pub fn update(&mut self, delta: Duration) {
+        if let Some(timer) = self.phase_timer.as_mut() {
+            timer.update(delta);
+        }
+    }
+    /// Whether the current phase has run out of time
+    pub fn phase_over(&self) -> bool {
+        matches!(self.phase_timer, Some(timer) if timer.ready)
+    }
+    /// Whether dead players get to respawn right now
+    pub fn allows_respawn(&self, game_mode: GameMode) -> bool {
+        !(game_mode == GameMode::LastPlayerStanding && self.phase == RoundPhase::Active)
+    }
+    /// Used by the server. Bump a player's round score, if the round is on.
+    pub fn score(&mut self, key: u64) {
+        if self.phase == RoundPhase::Active {
+            self.scores.score(key);
+        }
+    }
+    /// Used by the server. Decrement a player's round score, if the round is on.
+    pub fn penalize(&mut self, key: u64) {
+        if self.phase == RoundPhase::Active {
+            self.scores.penalize(key);
+        }
+    }
+}
+
+/// A team of players.  Only used when `GameSettings.teams` isn't zero.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Team {
+    /// The ID of the team. Matches `PlayerState.team` for everyone on the team.
+    pub id: u8,
+    /// The color everyone on the team wears
+    pub color: Color,
+    /// The team's total points.  Reset at the start of every round.
+    pub points: i32,
+}
+
+impl Team {
+    /// The client should never create a `Team` -- the server will do that.
+    pub fn new(id: u8, color: Color) -> Self {
+        Self {
+            id,
+            color,
+            points: 0,
+        }
+    }
+}
+
+/// A hill in a king-of-the-hill game
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hill {
+    /// The area of the hill
+    pub region: Region,
+    /// The ID of the player holding the hill -- the only living player on it.  `None` if nobody
+    /// is on the hill, or if more than one player is fighting over it.
+    pub holder: Option<u8>,
+    /// Counts down to the holder's next point.  Starts over whenever the holder changes.
+    pub point_timer: Timer,
+}
+
+impl Hill {
+    /// The client should never create a `Hill` -- the server will do that.
+    pub fn new(region: Region, game_settings: &GameSettings) -> Self {
+        Self {
+            region,
+            holder: None,
+            point_timer: Timer::from_millis(game_settings.hill_point_delay),
+        }
+    }
+}
+
+/// A team's flag in a capture-the-flag game.  Grab another team's flag and bring it to your own
+/// flag (while your flag is at home) to make a capture.  Touch your own flag after it has been
+/// dropped to send it home.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Flag {
+    /// The ID of the team the flag belongs to
+    pub team: u8,
+    /// Where the flag lives when nobody has taken it
+    pub home: Vec2,
+    /// Where the flag is right now
+    pub pos: Vec2,
+    /// The flag occupies a circle of this radius, in OpenGL units.
+    pub radius: f32,
+    /// The ID of the player carrying the flag, if anyone is
+    pub carrier: Option<u8>,
+    /// Counts down while the flag is lying on the ground away from home.  When it's ready, the
+    /// flag goes home by itself.
+    pub return_timer: Timer,
+}
+
+impl Flag {
+    /// The client should never create a `Flag` -- the server will do that.
+    pub fn new(team: u8, home: Vec2, game_settings: &GameSettings) -> Self {
+        Self {
+            team,
+            home,
+            pos: home,
+            radius: 0.03,
+            carrier: None,
+            return_timer: Timer::from_millis(game_settings.flag_return_delay),
+        }
+    }
+    /// Used by the server. Counts down the return timer while the flag is lying on the ground.
+    pub fn update(&mut self, delta: Duration) {
+        if self.carrier.is_none() && !self.is_home() {
+            self.return_timer.update(delta);
+        }
+    }
+    /// Whether the flag is sitting at home
+    pub fn is_home(&self) -> bool {
+        self.carrier.is_none() && self.pos == self.home
+    }
+    /// Whether the player is close enough to grab the flag
+    pub fn touches(&self, player_state: &PlayerState) -> bool {
+        distance(&self.pos, &player_state.pos) <= self.radius + player_state.radius
+    }
+    /// Used by the server when the flag's carrier drops it
+    pub fn put_down(&mut self) {
+        self.carrier = None;
+        self.return_timer.reset();
+    }
+    /// Used by the server to send the flag home
+    pub fn go_home(&mut self) {
+        self.carrier = None;
+        self.pos = self.home;
+    }
+}
+
+/// One phase of a shrinking safe zone.  The zone waits, then shrinks down to a smaller circle
+/// somewhere inside of itself.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ZonePhase {
+    /// Milliseconds. How long the zone waits before it starts shrinking
+    pub wait: u64,
+    /// Milliseconds. How long the zone takes to shrink
+    pub shrink: u64,
+    /// The radius the zone shrinks down to
+    pub radius: f32,
+    /// How much damage per second players outside of the zone take during this phase
+    pub damage_per_second: f32,
+}
+
+/// The safe zone.  The darkness closes in a phase at a time, and anyone caught outside of the zone
+/// takes damage until they get back in.  Only used when `GameSettings.zone_phases` isn't empty.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SafeZone {
+    /// The center of the zone right now
+    pub center: Vec2,
+    /// The radius of the zone right now, in OpenGL units
+    pub radius: f32,
+    /// The center of the zone once it's done shrinking
+    pub next_center: Vec2,
+    /// The radius of the zone once it's done shrinking
+    pub next_radius: f32,
+    /// Which of the `GameSettings.zone_phases` the zone is in.  Once this is past the last phase,
+    /// the zone is done shrinking.
+    pub phase: usize,
+    /// Whether the zone is shrinking right now (as opposed to waiting to shrink)
+    pub shrinking: bool,
+    /// Counts down to when the zone starts shrinking, or is done shrinking
+    pub timer: Timer,
+    // Private! No docs for you.  Where the zone was when it started shrinking.
+    start_center: Vec2,
+    start_radius: f32,
+}
+
+impl SafeZone {
+    /// Used by the server to start a zone that covers the whole arena.  Returns `None` if the
+    /// game settings don't have any zone phases.
+    pub fn new<R: Rng>(game_settings: &GameSettings, rng: &mut R) -> Option<Self> {
+        let first = game_settings.zone_phases.first()?;
+        let (min, max) = game_settings.map.boundary.bounds();
+        let center = (min + max) * 0.5;
+        let radius = distance(&center, &max);
+        let mut zone = Self {
+            center,
+            radius,
+            next_center: center,
+            next_radius: radius,
+            phase: 0,
+            shrinking: false,
+            timer: Timer::from_millis(first.wait),
+            start_center: center,
+            start_radius: radius,
+        };
+        zone.pick_next(first.radius, rng);
+        Some(zone)
+    }
+    // Pick a random spot for the next zone that fits entirely inside of the current one
+    fn pick_next<R: Rng>(&mut self, radius: f32, rng: &mut R) {
+        let radius = radius.min(self.radius);
+        let angle = rng.gen::<f32>() * 2.0 * PI;
+        let offset = rng.gen::<f32>() * (self.radius - radius);
+        self.next_center = self.center + Vec2::new(angle.cos(), angle.sin()) * offset;
+        self.next_radius = radius;
+    }
+    /// Used by the server. Counts down the timer, and shrinks the zone.
+    pub fn update<R: Rng>(&mut self, delta: Duration, phases: &[ZonePhase], rng: &mut R) {
+        let phase = match phases.get(self.phase) {
+            Some(phase) => phase,
+            None => return,
+        };
+        self.timer.update(delta);
+        if !self.shrinking {
+            if self.timer.ready {
+                self.shrinking = true;
+                self.start_center = self.center;
+                self.start_radius = self.radius;
+                self.timer = Timer::from_millis(phase.shrink);
+            }
+            return;
+        }
+        if !self.timer.ready {
+            let progress = 1.0 - self.timer.time_left_percent();
+            self.center = self.start_center + (self.next_center - self.start_center) * progress;
+            self.radius = self.start_radius + (self.next_radius - self.start_radius) * progress;
+            return;
+        }
+        // Done shrinking.  On to the next phase, if there is one.
+        self.center = self.next_center;
+        self.radius = self.next_radius;
+        self.shrinking = false;
+        self.phase += 1;
+        if let Some(next) = phases.get(self.phase) {
+            self.timer = Timer::from_millis(next.wait);
+            self.pick_next(next.radius, rng);
+        }
+    }
+    /// Whether the zone has gone through all of its phases and is done shrinking
+    pub fn is_done(&self, phases: &[ZonePhase]) -> bool {
+        self.phase >= phases.len()
+    }
+    /// Whether `pos` is inside the zone
+    pub fn contains(&self, pos: &Vec2) -> bool {
+        distance(&self.center, pos) <= self.radius
+    }
+    /// How much damage per second players outside of the zone take right now.  Once the zone is
+    /// done shrinking, it's the damage from the last phase.
+    pub fn damage_per_second(&self, phases: &[ZonePhase]) -> f32 {
+        phases
+            .get(self.phase)
+            .or_else(|| phases.last())
+            .map_or(0.0, |phase| phase.damage_per_second)
+    }
+}
+
+/// The kinds of things that show up in the arena for players to pick up
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum PickupKind {
+    /// Gives back some health right away
+    HealthPotion,
+    /// Lets you move faster for a while
+    SpeedBoost,
+    /// Makes your attacks do more damage for a while
+    DamageBuff,
+    /// Lets less damage through for a while
+    Shield,
+    /// Swaps the weapon you're holding for this one
+    Weapon(WeaponKind),
+}
+
+impl PickupKind {
+    /// All of the pickup kinds
+    pub fn all() -> Vec<Self> {
+        vec![
+            PickupKind::HealthPotion,
+            PickupKind::SpeedBoost,
+            PickupKind::DamageBuff,
+            PickupKind::Shield,
+            PickupKind::Weapon(WeaponKind::RustySword),
+            PickupKind::Weapon(WeaponKind::Dagger),
+            PickupKind::Weapon(WeaponKind::Greatsword),
+            PickupKind::Weapon(WeaponKind::Spear),
+            PickupKind::Weapon(WeaponKind::Axe),
+            PickupKind::Weapon(WeaponKind::Bow),
+            PickupKind::Weapon(WeaponKind::ThrowingKnives),
+        ]
+    }
+}
+
+/// Something lying around the arena, waiting for someone to walk over it
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Pickup {
+    /// Unique (per server run) ID of the pickup, so you can track it across frames
+    pub id: u64,
+    /// What kind of pickup it is
+    pub kind: PickupKind,
+    /// The position of the pickup in OpenGL units.
+    pub pos: Vec2,
+    /// The pickup occupies a circle of this radius, in OpenGL units.
+    pub radius: f32,
+}
+
+impl Pickup {
+    /// The client should never create a `Pickup` -- the server will do that.
+    pub fn new(id: u64, kind: PickupKind, pos: Vec2) -> Self {
+        Self {
+            id,
+            kind,
+            pos,
+            radius: 0.03,
+        }
+    }
+    /// Whether the player is close enough to pick it up
+    pub fn touches(&self, player_state: &PlayerState) -> bool {
+        distance(&self.pos, &player_state.pos) <= self.radius + player_state.radius
+    }
+}
+
+/// A temporary boost a player got from a pickup
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Buff {
+    /// Which pickup the buff came from
+    pub kind: PickupKind,
+    /// When this timer is ready, the buff wears off
+    pub timer: Timer,
+}
+
+/// What killed a player
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum DeathCause {
+    /// Slain by another player, with this weapon
+    Player { killer: u8, weapon: WeaponKind },
+    /// Eaten by a grue for leaving the arena
+    Grue,
+    /// Stood in a hazard for too long
+    Hazard,
+    /// Got caught outside of the safe zone
+    Zone,
+}
+
+/// One kill in the kill feed.  Names are included because the players might have left by the time
+/// you get around to showing the kill.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct KillFeedEntry {
+    /// The ID of the player who died
+    pub victim: u8,
+    /// The name of the player who died
+    pub victim_name: String,
+    /// What killed them
+    pub cause: DeathCause,
+    /// The name of the player who killed them, if a player did
+    pub killer_name: Option<String>,
+    /// The IDs of the other players who hurt the victim shortly before they died
+    pub assists: Vec<u8>,
+    /// When this timer is ready, the kill drops out of the feed
+    pub timer: Timer,
+}
+
+impl fmt::Display for KillFeedEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.cause, &self.killer_name) {
+            (DeathCause::Player { weapon, .. }, Some(killer_name)) => write!(
+                f,
+                "{} slew {} with a {}",
+                killer_name,
+                self.victim_name,
+                Weapon::from_kind(*weapon).description
+            ),
+            (DeathCause::Player { .. }, None) => write!(f, "{} was slain", self.victim_name),
+            (DeathCause::Grue, _) => write!(f, "{} was eaten by a grue", self.victim_name),
+            (DeathCause::Hazard, _) => write!(f, "{} stood in the wrong place", self.victim_name),
+            (DeathCause::Zone, _) => write!(f, "{} was lost to the dark", self.victim_name),
+        }
+    }
+}
+
 /// A player event that has happened to your player this frame!  Note that it's possible to receive
 /// a whole bunch of events in the same frame.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -277,19 +1674,93 @@ pub enum PlayerEvent {
     AttackHit { id: u8 },
     /// Player has attacked, but not hit anyone.
     AttackMiss,
-    /// Player has died
-    Die,
+    /// Player has died, and this is what killed them
+    Die { cause: DeathCause },
     /// Player has spawned
     Spawn,
     /// Player has received damage
     TookDamage,
     /// Player has joined the game
     Join,
+    /// Player hasn't done anything in a while, so they're sitting out.  See
+    /// `GameSettings.afk_delay`.
+    Afk,
+    /// Player started playing again after being AFK
+    BackFromAfk,
+    /// Player has switched to a different weapon
+    ChangeWeapon { kind: WeaponKind },
+    /// Player has fired a projectile with this projectile id
+    Fire { projectile_id: u64 },
+    /// Player has been hit by a projectile fired by player id
+    HitByProjectile { id: u8 },
+    /// Player has blocked an attack from player id
+    Block { id: u8 },
+    /// Player has parried an attack from player id
+    Parry { id: u8 },
+    /// Player's attack was blocked by player id
+    AttackBlocked { id: u8 },
+    /// Player's attack was parried by player id.  Ouch, that's going to slow you down.
+    AttackParried { id: u8 },
+    /// Player has dodged
+    Dodge,
+    /// Player has picked up the flag belonging to team id
+    FlagPickup { team: u8 },
+    /// Player has dropped the flag belonging to team id
+    FlagDrop { team: u8 },
+    /// Player has captured the flag belonging to team id
+    FlagCapture { team: u8 },
+    /// Player has sent their own team's dropped flag home
+    FlagReturn { team: u8 },
+    /// Player has picked up a pickup of this kind
+    Pickup { kind: PickupKind },
+    /// Player's buff of this kind has worn off
+    BuffExpired { kind: PickupKind },
+    /// Player has killed player id
+    Kill { id: u8 },
+    /// Player has helped kill player id (they hurt them shortly before somebody else killed them)
+    Assist { id: u8 },
+}
+
+/// The kinds of weapons the server knows about.  Every `Weapon` has one of these, so a client can
+/// look at `PlayerState.weapon.kind` to decide how to render the weapon a player is holding.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum WeaponKind {
+    /// The classic.  Swings all the way around you, middling in every way.
+    RustySword,
+    /// Quick, short, and only hits what's in front of you.
+    Dagger,
+    /// Slow and heavy, but it swings all the way around and sends people flying.
+    Greatsword,
+    /// Long reach in a narrow line straight ahead.
+    Spear,
+    /// Hits hard in a wide arc in front of you.
+    Axe,
+    /// Shoots arrows.  Useless up close.
+    Bow,
+    /// Throws small knives a short distance.
+    ThrowingKnives,
+}
+
+impl WeaponKind {
+    /// All of the weapon kinds, in case you want to let a player choose one.
+    pub fn all() -> Vec<WeaponKind> {
+        vec![
+            WeaponKind::RustySword,
+            WeaponKind::Dagger,
+            WeaponKind::Greatsword,
+            WeaponKind::Spear,
+            WeaponKind::Axe,
+            WeaponKind::Bow,
+            WeaponKind::ThrowingKnives,
+        ]
+    }
 }
 
 /// A weapon a player may hold.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Weapon {
+    /// Which kind of weapon this is.  Useful for picking an image to draw.
+    pub kind: WeaponKind,
     /// Something like "Rusty Sword", "Shiny Sword", "Rusty Spear", etc.
     pub description: String,
     /// How much damage the weapon can cause
@@ -298,17 +1769,66 @@ pub struct Weapon {
     pub attack_timer: Timer,
     /// How far attacks reach from your player, in OpenGL units.
     pub radius: f32,
+    /// How wide the attack is, in radians, centered on the direction the player is facing.  A
+    /// weapon with an arc of `2π` hits everything around you.
+    pub arc: f32,
+    /// While attacking, the player's maximum velocity is multiplied by this amount. `0.5` means
+    /// half speed.
+    pub attack_speed_multiplier: f32,
+    /// How hard a hit from this weapon shoves the defender, in OpenGL units per second.
+    pub knockback: f32,
+    /// Ranged weapons fire a projectile instead of hitting everyone within `radius`.  `None` for
+    /// melee weapons.
+    pub projectile: Option<ProjectileSpec>,
 }
 
 impl Weapon {
+    /// Create the default weapon, a Rusty Sword
     pub fn new() -> Self {
+        Self::from_kind(WeaponKind::RustySword)
+    }
+    /// Create a weapon of the given kind, with all of that kind's stats.
+    pub fn from_kind(kind: WeaponKind) -> Self {
+        let (description, damage, radius, arc, attack_ms, attack_speed_multiplier, knockback) =
+            match kind {
+                WeaponKind::RustySword => ("Rusty Sword", 26.0, 0.1, 2.0 * PI, 500, 0.5, 0.8),
+                WeaponKind::Dagger => ("Dagger", 14.0, 0.06, 0.5 * PI, 200, 0.9, 0.3),
+                WeaponKind::Greatsword => ("Greatsword", 45.0, 0.14, 2.0 * PI, 1000, 0.3, 1.6),
+                WeaponKind::Spear => ("Spear", 30.0, 0.2, PI / 6.0, 700, 0.6, 1.0),
+                WeaponKind::Axe => ("Axe", 38.0, 0.1, PI, 800, 0.4, 1.2),
+                WeaponKind::Bow => ("Bow", 20.0, 0.0, 0.0, 800, 0.7, 0.4),
+                WeaponKind::ThrowingKnives => ("Throwing Knives", 12.0, 0.0, 0.0, 350, 0.8, 0.15),
+            };
+        let projectile = match kind {
+            WeaponKind::Bow => Some(ProjectileSpec {
+                speed: 1.2,
+                radius: 0.01,
+                lifetime: 1500,
+            }),
+            WeaponKind::ThrowingKnives => Some(ProjectileSpec {
+                speed: 0.8,
+                radius: 0.015,
+                lifetime: 600,
+            }),
+            _ => None,
+        };
         Self {
-            description: "Rusty Sword".to_string(),
-            damage: 26.0,
-            radius: 0.1,
-            attack_timer: Timer::from_millis(500),
+            kind,
+            description: description.to_string(),
+            damage,
+            attack_timer: Timer::from_millis(attack_ms),
+            radius,
+            arc,
+            attack_speed_multiplier,
+            knockback,
+            projectile,
         }
     }
+    /// Whether something in the direction `angle` (radians) falls within the arc of this weapon
+    /// when it is swung by a player facing `facing` (radians).
+    pub fn in_arc(&self, facing: f32, angle: f32) -> bool {
+        angle_within_arc(facing, angle, self.arc)
+    }
 }
 
 impl Default for Weapon {
@@ -317,6 +1837,80 @@ impl Default for Weapon {
     }
 }
 
+/// How a ranged weapon's projectiles behave.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ProjectileSpec {
+    /// How fast the projectile flies, in OpenGL units per second
+    pub speed: f32,
+    /// The projectile occupies a circle of this radius, in OpenGL units.
+    pub radius: f32,
+    /// Milliseconds. How long the projectile flies before it falls to the ground.
+    pub lifetime: u64,
+}
+
+/// Something flying through the arena, like an arrow or a thrown knife.  The server creates,
+/// moves and removes projectiles, and sends all the ones currently in flight in each `GameState`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Projectile {
+    /// Unique (per server run) ID of the projectile, so you can track it across frames
+    pub id: u64,
+    /// The ID of the player who fired the projectile
+    pub owner: u8,
+    /// Which kind of weapon fired the projectile
+    pub kind: WeaponKind,
+    /// The position of the projectile in OpenGL units.
+    pub pos: Vec2,
+    /// Current velocity of the projectile
+    pub velocity: Vec2,
+    /// The projectile occupies a circle of this radius, in OpenGL units.
+    pub radius: f32,
+    /// How much damage the projectile causes when it hits someone
+    pub damage: f32,
+    /// How hard the projectile shoves whoever it hits, in OpenGL units per second.
+    pub knockback: f32,
+    /// When this timer is ready, the projectile is removed
+    pub lifetime: Timer,
+}
+
+impl Projectile {
+    /// The client should never create a `Projectile` -- the server will do that.
+    pub fn new(id: u64, owner: u8, pos: Vec2, direction: f32, weapon: &Weapon) -> Option<Self> {
+        let spec = weapon.projectile?;
+        Some(Self {
+            id,
+            owner,
+            kind: weapon.kind,
+            pos,
+            velocity: Vec2::new(direction.cos(), direction.sin()) * spec.speed,
+            radius: spec.radius,
+            damage: weapon.damage,
+            knockback: weapon.knockback,
+            lifetime: Timer::from_millis(spec.lifetime),
+        })
+    }
+    /// Move the projectile along its velocity and count down its lifetime.  Returns the position
+    /// the projectile was at before it moved, so the server can check the whole path for hits.
+    pub fn update(&mut self, delta: Duration) -> Vec2 {
+        let previous = self.pos;
+        self.lifetime.update(delta);
+        self.pos += self.velocity * delta.f32();
+        previous
+    }
+    /// If the path from `from` to `self.pos` passes within `radius` (plus the projectile's own
+    /// radius) of `center`, returns how far along the path (`0.0` at `from`, `1.0` at `self.pos`)
+    /// it gets there.  This way fast projectiles can't skip over a player between frames, and the
+    /// server can tell who was first in line.
+    pub fn swept_hit(&self, from: &Vec2, center: &Vec2, radius: f32) -> Option<f32> {
+        let reach = radius + self.radius;
+        if distance(from, center) <= reach {
+            return Some(0.0);
+        }
+        circle_crossings(from, &self.pos, center, reach)
+            .into_iter()
+            .next()
+    }
+}
+
 /// Represents the state of the player on the server for the current `GameState`.  The server always
 /// creates `PlayeState`s, updates them, and sends them to the client each frame inside a
 /// `GameState`. The client is free to modify its local copy (for example, to remove `PlayerEvent`s
@@ -330,8 +1924,26 @@ pub struct PlayerState {
     pub id: u8,
     /// The name of the player
     pub name: String,
-    /// The color of the player
+    /// A number the server gives the player that stays the same from game to game, even if they
+    /// change their `name`.  High scores are kept by key -- see `Score::key()`.  The identity the
+    /// player set (`ConnectionToServer::set_identity()`) never leaves the server; this is what
+    /// everyone else sees instead.
+    pub key: u64,
+    /// Which skin the player picked.  See `GameSettings.skins`.
+    pub skin: u16,
+    /// Something to show along with the player's name, like "the Unready"
+    pub title: Option<String>,
+    /// The color of the player.  On a team, this is the team's color.
     pub color: Color,
+    /// The ID of the team the player is on, if there are teams
+    pub team: Option<u8>,
+    /// The team ID of the flag the player is carrying, if they are carrying one.  Carrying a flag
+    /// slows you down.
+    pub flag: Option<u8>,
+    /// Where the player stands in the high scores.  `1` is first place.  Only the top 10 high
+    /// scores come with each `GameState`, so this is how you find out how you're doing if you're
+    /// further down.
+    pub rank: usize,
     /// The position of the player in OpenGL units.
     pub pos: Vec2,
     /// The direction the player is facing, in radians
@@ -340,16 +1952,43 @@ pub struct PlayerState {
     pub radius: f32,
     /// Current velocity of the player
     pub velocity: Vec2,
+    /// Extra velocity from being knocked back by a hit.  Unlike `velocity`, this isn't limited to
+    /// the maximum velocity, so a good hit can send you flying.  It is added to `velocity` when
+    /// moving, and drags back down to zero quickly.
+    pub knockback: Vec2,
     /// Current health of the player [0.0, 100.0]
     pub health: f32,
-    // Private! No docs for you.  We use this when we respawn.
+    // Private! No docs for you.  We use these when we respawn.
     starting_health: f32,
+    max_stamina: f32,
     /// Current weapon of the player
     pub weapon: Weapon,
+    /// Current stamina of the player [0.0, `GameSettings.max_stamina`].  Blocking and dodging use
+    /// it up, and it comes back over time.
+    pub stamina: f32,
+    /// Whether the player is currently holding up a block
+    pub blocking: bool,
+    /// Counts down from when the player started blocking.  While it isn't ready, a block is a
+    /// parry.
+    pub parry_timer: Timer,
+    /// Counts down from when the player dodged.  While it isn't ready, the player is dodging.
+    pub dodge_timer: Timer,
+    /// Counts down from when the player spawned.  While it isn't ready, nothing can hurt the player
+    /// (except the grue).  You might want to draw something to show that.
+    pub spawn_protection_timer: Timer,
+    /// Temporary boosts from pickups.  Each one wears off when its timer is ready.
+    pub buffs: Vec<Buff>,
+    /// How long the player has been alive this time around.  Starts over when they die.
+    pub time_alive: Duration,
     /// Any player events that have occurred to the player this frame
     pub player_events: Vec<PlayerEvent>,
-    /// How long the server will wait to get input from you before disconnecting you
+    /// How long the server will wait to get a heartbeat from you before disconnecting you
     pub drop_timer: Timer,
+    /// How long the server will wait for you to do something before deciding you're AFK
+    pub afk_timer: Timer,
+    /// Whether the player is AFK and sitting out.  AFK players don't spawn until they come back,
+    /// and they only get to come back if the game isn't full.
+    pub afk: bool,
     /// How long until the player respawns.  If respawn_timer.ready == false, then the player is
     /// dead and you should seriously consider indicating that visually somehow, even if only by not
     /// displaying the player.
@@ -371,22 +2010,46 @@ impl PlayerState {
         color: Color,
         pos: Vec2,
         radius: f32,
+        weapon: Weapon,
     ) -> Self {
         let mut respawn_timer = Timer::from_millis(game_settings.respawn_delay);
         respawn_timer.set_millis_transient(2000); // spawn more quickly on initial connect
+        let mut parry_timer = Timer::from_millis(game_settings.parry_window);
+        parry_timer.finish();
+        let mut dodge_timer = Timer::from_millis(game_settings.dodge_duration);
+        dodge_timer.finish();
+        let mut spawn_protection_timer = Timer::from_millis(game_settings.spawn_protection);
+        spawn_protection_timer.finish();
         Self {
             id,
+            key: 0,
             name,
+            skin: 0,
+            title: None,
             color,
+            team: None,
+            flag: None,
+            rank: 0,
             pos,
             direction: 0.0,
             radius,
             velocity: Vec2::new(0., 0.),
+            knockback: Vec2::new(0., 0.),
             health: 100.0,
             starting_health: 100.0,
-            weapon: Weapon::new(),
+            max_stamina: game_settings.max_stamina,
+            weapon,
+            stamina: game_settings.max_stamina,
+            blocking: false,
+            parry_timer,
+            dodge_timer,
+            spawn_protection_timer,
+            buffs: Vec::new(),
+            time_alive: Duration::from_secs(0),
             player_events: vec![PlayerEvent::Join],
             drop_timer: Timer::from_millis(game_settings.drop_delay),
+            afk_timer: Timer::from_millis(game_settings.afk_delay),
+            afk: false,
             respawn_timer,
             dead: true,
             joining: true,
@@ -396,25 +2059,92 @@ impl PlayerState {
     /// time.
     pub fn update(&mut self, delta: Duration) {
         self.weapon.attack_timer.update(delta);
+        self.parry_timer.update(delta);
+        self.dodge_timer.update(delta);
+        self.spawn_protection_timer.update(delta);
         self.drop_timer.update(delta);
+        self.afk_timer.update(delta);
         self.respawn_timer.update(delta);
+        if !self.dead {
+            self.time_alive += delta;
+        }
+        for buff in self.buffs.iter_mut() {
+            buff.timer.update(delta);
+            if buff.timer.ready {
+                self.player_events
+                    .push(PlayerEvent::BuffExpired { kind: buff.kind });
+            }
+        }
+        self.buffs.retain(|buff| !buff.timer.ready);
     }
     /// Used by the server to reset things that have been taken care of last frame.
     pub fn new_frame(&mut self) {
         self.player_events.clear();
     }
     /// Used by the server when a player needs to die
-    pub fn die(&mut self, msg: &str) {
+    pub fn die(&mut self, msg: &str, cause: DeathCause) {
         println!("{}", msg);
         self.health = -1.0;
         self.respawn_timer.reset();
-        self.player_events.push(PlayerEvent::Die);
+        self.player_events.push(PlayerEvent::Die { cause });
+        self.blocking = false;
+        self.buffs.clear();
+        self.time_alive = Duration::from_secs(0);
         self.dead = true;
     }
+    /// Used by the server when a player gets a new weapon
+    pub fn equip(&mut self, weapon: Weapon) {
+        self.player_events
+            .push(PlayerEvent::ChangeWeapon { kind: weapon.kind });
+        self.weapon = weapon;
+    }
+    /// Used by the server when a player drinks a health potion.  You can't heal past the health you
+    /// started with.
+    pub fn heal(&mut self, amount: f32) {
+        self.health = (self.health + amount).min(self.starting_health);
+    }
+    /// Used by the server when a player picks up a buff.  Picking up a buff you already have
+    /// starts its timer over.
+    pub fn add_buff(&mut self, kind: PickupKind, duration: u64) {
+        self.buffs.retain(|buff| buff.kind != kind);
+        self.buffs.push(Buff {
+            kind,
+            timer: Timer::from_millis(duration),
+        });
+    }
+    /// Whether the player has a buff of this kind right now
+    pub fn has_buff(&self, kind: PickupKind) -> bool {
+        self.buffs.iter().any(|buff| buff.kind == kind)
+    }
+    /// Used by the server when a player gets hit hard enough to be shoved around
+    pub fn apply_knockback(&mut self, impulse: Vec2) {
+        self.knockback += impulse;
+    }
+    /// Whether the player is in the middle of a dodge
+    pub fn is_dodging(&self) -> bool {
+        !self.dodge_timer.ready
+    }
+    /// Whether the player just spawned and can't be hurt yet
+    pub fn is_protected(&self) -> bool {
+        !self.spawn_protection_timer.ready
+    }
+    /// Whether something at `pos` is within an arc of `arc` radians centered on the direction the
+    /// player is facing.
+    pub fn is_facing(&self, pos: &Vec2, arc: f32) -> bool {
+        angle_within_arc(self.direction, angle_facing(&self.pos, pos), arc)
+    }
+    /// Whether the other player is on the same team as this player.  Nobody is anybody's teammate
+    /// when there aren't teams, and you aren't your own teammate.
+    pub fn is_teammate(&self, other: &PlayerState) -> bool {
+        self.id != other.id && self.team.is_some() && self.team == other.team
+    }
     /// Used by the server when a player needs to spawn
     pub fn respawn(&mut self, pos: Vec2, msg: &str) {
         println!("{}", msg);
         self.pos = pos;
+        self.knockback = Vec2::zeros();
+        self.stamina = self.max_stamina;
+        self.spawn_protection_timer.reset();
         self.health = self.starting_health;
         self.player_events.push(PlayerEvent::Spawn);
         self.dead = false;
@@ -447,7 +2177,127 @@ pub struct GameState {
     pub player_states: HashMap<u8, PlayerState>,
     /// High scores. The server will only send the top 10.
     pub high_scores: HighScores,
+    /// The current round, including the scores for just this round.
+    pub round: Round,
+    /// All of the projectiles currently in flight.  A projectile that was in the previous frame but
+    /// isn't in this one has either hit someone, hit the edge of the arena, or run out of steam.
+    pub projectiles: Vec<Projectile>,
+    /// All of the teams, with their points.  Empty if there aren't teams.
+    pub teams: Vec<Team>,
+    /// The hills in a king-of-the-hill game.  Empty in other game modes.
+    pub hills: Vec<Hill>,
+    /// The flags in a capture-the-flag game.  Empty in other game modes.
+    pub flags: Vec<Flag>,
+    /// The kills from the last few seconds, oldest first
+    pub kill_feed: Vec<KillFeedEntry>,
+    /// All of the pickups lying around the arena.  A pickup that was in the previous frame but
+    /// isn't in this one has been picked up.
+    pub pickups: Vec<Pickup>,
+    /// The safe zone, if there is one.  Stay inside it!  The zone shrinks towards `next_center`
+    /// and `next_radius`, so you can draw where the darkness is headed.
+    pub zone: Option<SafeZone>,
+    /// Everyone who is watching instead of playing
+    pub spectators: Vec<Spectator>,
+}
+
+/// A chat message from a player.  See `ConnectionToServer::send_chat()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatMessage {
+    /// The id of the player who sent the message
+    pub id: u8,
+    /// The name of the player who sent the message
+    pub name: String,
+    /// If this message was only for one team, then this is the team.  Otherwise, `None`.
+    pub team: Option<u8>,
+    pub text: String,
+    /// When the server got the message, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.team {
+            Some(_) => write!(f, "[team] {}: {}", self.name, self.text),
+            None => write!(f, "{}: {}", self.name, self.text),
+        }
+    }
+}
+
+/// One of the arenas the server is running.  See `ConnectionToServer::list_rooms()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RoomInfo {
+    /// Pass this to `ConnectionToServer::set_room()` to play in this room
+    pub room: u8,
+    pub name: String,
+    pub game_mode: GameMode,
+    pub players: u8,
+    pub max_players: u8,
+    pub spectators: u16,
+    /// If this is a match room, how many players get matched up to play in it.  You can't join a
+    /// match room yourself -- get in line with `ConnectionToServer::queue()` instead.
+    pub match_size: u8,
+}
+
+impl fmt::Display for RoomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<3} {} ({:?}) - {}/{} players, {} watching",
+            self.room, self.name, self.game_mode, self.players, self.max_players, self.spectators
+        )?;
+        if self.match_size > 0 {
+            write!(f, " [matches of {}]", self.match_size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where you stand in the matchmaking queue.  See `ConnectionToServer::queue_status()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum QueueStatus {
+    /// Still looking for a match.  You're number `position` in line (`1` is next) out of `queued`
+    /// players, and you've been waiting for `waited` milliseconds.
+    Waiting {
+        position: u16,
+        queued: u16,
+        waited: u64,
+    },
+    /// You're in a match in room `room`!  Join it right away, before the server gives your spot
+    /// away.  Only the `join_ticket` gets you in, and only once.  `ConnectionToServer` hangs on to
+    /// it and hands it over when you `join()`.
+    Matched { room: u8, join_ticket: u64 },
+    /// Nobody good to play against showed up in time.  You can always queue again.
+    TimedOut,
+}
+
+impl fmt::Display for QueueStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueStatus::Waiting {
+                position,
+                queued,
+                waited,
+            } => write!(
+                f,
+                "Waiting for a match: {} of {} in line ({}s)",
+                position,
+                queued,
+                waited / 1000
+            ),
+            QueueStatus::Matched { room, .. } => write!(f, "Matched in room {}", room),
+            QueueStatus::TimedOut => write!(f, "No match found"),
+        }
+    }
 }
+
+/// Someone watching the game instead of playing.  See `ConnectionToServer::spectate()`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Spectator {
+    pub name: String,
+    /// The id of the player the spectator is following, if they're following anyone
+    pub following: Option<u8>,
+}
+
 /// Clients should send `PlayerInput`s to the server often.  The quicker the server gets inputs, the
 /// more accurate the simulation will be.  But of course, you also shouldn't overload the server
 /// with too much traffic, because that's bad too.  Good rule of thumb: Coalesce 15 milliseconds
@@ -481,6 +2331,13 @@ pub struct PlayerInput {
     pub move_amount: Vec2,
     /// What direction your player is facing. You can turn instantly, you lucky dog.
     pub direction: f32,
+    /// Whether you are holding up a block.  Blocking only works while you have stamina, and you
+    /// can't attack while blocking.
+    pub block: bool,
+    /// Whether you are attempting to dodge in the direction you are moving (or facing, if you
+    /// aren't moving).  Dodging costs stamina.  You only dodge once each time this turns on, no
+    /// matter how long you leave it on.
+    pub dodge: bool,
 }
 
 impl Default for PlayerInput {
@@ -490,6 +2347,8 @@ impl Default for PlayerInput {
             attack: false,
             move_amount: Vec2::zeros(),
             direction: 0.0,
+            block: false,
+            dodge: false,
         }
     }
 }
@@ -502,14 +2361,63 @@ impl PlayerInput {
             attack: false,
             move_amount: Vec2::zeros(),
             direction: 0.0,
+            block: false,
+            dodge: false,
         }
     }
     /// Used by the server. Unlikely to be used by the client.
     pub fn coalesce(&mut self, new: PlayerInput) {
-        // Any attack sticks
+        // Any attack or dodge sticks
         self.attack = self.attack || new.attack;
+        self.dodge = self.dodge || new.dodge;
         // Anything else the new value wins
         self.move_amount = new.move_amount;
         self.direction = new.direction;
+        self.block = new.block;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn high_scores(count: u64) -> HighScores {
+        let mut high_scores = HighScores::new();
+        for key in 0..count {
+            high_scores.add_player(key, &format!("Player {}", key));
+        }
+        high_scores
+    }
+
+    fn ranks(page: &ScoreboardPage) -> Vec<usize> {
+        page.scores.iter().map(|ranked| ranked.rank).collect()
+    }
+
+    #[test]
+    fn first_page_starts_at_the_top() {
+        let page = high_scores(5).page(0, 2);
+        assert_eq!((page.page, page.pages, page.total), (0, 3, 5));
+        assert_eq!(ranks(&page), vec![1, 2]);
+    }
+
+    #[test]
+    fn last_page_can_be_short() {
+        let page = high_scores(5).page(2, 2);
+        assert_eq!((page.page, page.pages, page.total), (2, 3, 5));
+        assert_eq!(ranks(&page), vec![5]);
+    }
+
+    #[test]
+    fn pages_past_the_end_are_empty() {
+        let high_scores = high_scores(5);
+        for &page in &[3, usize::MAX] {
+            let page = high_scores.page(page, 2);
+            assert_eq!((page.pages, page.total), (3, 5));
+            assert!(page.scores.is_empty());
+        }
+        assert!(high_scores.page(1, usize::MAX).scores.is_empty());
+        // Nobody gets a page with nothing on it by asking for pages with nothing on them
+        assert_eq!(ranks(&high_scores.page(0, 0)), vec![1]);
+        assert_eq!(HighScores::new().page(0, 10).pages, 0);
     }
 }
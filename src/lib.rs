@@ -33,14 +33,128 @@
 //! direction (which is _not_ instant, the server implements some rudimentary movement physics) and
 //! attempt to attack other players with your sword.  If another player is within the radius of
 //! your sword reach when you attack, they will be hurt and their health will go down.  You will be
-//! limited to 50% movement speed while attacking, so try to time your attack to when they will be
-//! successful.
+//! limited to 50% movement speed while attacking with the rusty sword, so try to time your attack
+//! to when they will be successful.
+//!
+//! The rusty sword isn't the only weapon in the arena.  You can pick a different `WeaponKind` when
+//! you join (daggers, greatswords, spears, axes, bows and throwing knives).  Each weapon has its
+//! own damage, reach, arc (how much of the circle around you it hits), attack delay, knockback, and
+//! how much it slows you down while attacking.  Bows and throwing knives don't swing at all -- they
+//! fire projectiles which fly across the arena until they hit someone, fly off into the dark, or
+//! run out of steam.  Everything in flight is in `GameState.projectiles`, so you'll want to draw
+//! those too.
+//!
+//! Players can't walk through each other -- you'll bump into anyone in your way.  Getting hit also
+//! knocks you back (some weapons much more than others), so watch your back when you're fighting
+//! near the edge of the arena.
+//!
+//! You don't have to just stand there and take it, either.  Hold up a block (`Action2`) to take
+//! much less damage from hits coming from in front of you, or dodge (`Action3`) to dash out of the
+//! way -- nothing can hit you mid-dodge.  Both use up stamina, which comes back while you aren't
+//! blocking.  Time your block just right, and you'll parry the hit completely, leaving the attacker
+//! unable to attack for a while.
+//!
+//! The arena doesn't have to be an empty square.  The server can load a map with a differently
+//! shaped edge, obstacles that block players and projectiles, hazards that hurt to stand in, and
+//! spawn points.  The map is in `GameSettings.map`, so you can draw it.
 //!
 //! You start with some health. When you are hit, you lose health.  When the health runs out, you
 //! die and the person who killed you gets a point.  After a respawn delay, you respawn with full
-//! health.  If you attempt to run from the arena, you are likely to be eaten by a grue.  Being
-//! eaten by a grue causes you to lose a point, but there is no point penalty when another player
-//! kills you.
+//! health, as far away from everyone else as the server can manage.  Nothing can hurt you for a
+//! couple of seconds after you spawn -- unless you attack, which ends your protection early.  If
+//! you attempt to run from the arena, you are likely to be eaten by a grue.  Being eaten by a grue
+//! causes you to lose a point, but there is no point penalty when another player kills you.
+//!
+//! When you die, `PlayerEvent::Die` tells you what killed you.  Whoever landed the killing blow
+//! gets a `PlayerEvent::Kill`, and anyone else who hurt you shortly before gets a
+//! `PlayerEvent::Assist`.  `GameState.kill_feed` has everyone's kills from the last few seconds,
+//! ready for you to show as "Alice slew Bob with a Rusty Sword".
+//!
+//! There's more to a score than points, too.  Every `Score` has `Stats`: kills, deaths, damage
+//! dealt and taken, accuracy, streaks, and how long you've stayed alive.  Ask the server for the
+//! whole scoreboard with `ConnectionToServer::get_scoreboard()`, and sort it however you like with
+//! `HighScores::sorted_by()`.  Points mostly go to whoever plays the longest, so every score also
+//! has a rating that goes up when you kill someone and down when you get killed -- more so when the
+//! other player is rated higher.  When there are teams, the server uses ratings to keep them even.
+//! Only the top 10 scores come with each `GameState`, but `PlayerState.rank` always tells you
+//! where you stand, and `ConnectionToServer::get_scoreboard_page()` fetches the rest of the
+//! scoreboard a page at a time.
+//!
+//! The server saves the high scores, so they're still there after it restarts, until it starts a
+//! new season (`GameSettings.season`).  Scores are kept by identity instead of by name -- call
+//! `ConnectionToServer::set_identity()` before you join and your scores will follow you even if
+//! you change your name.  Your identity stays a secret on the server.  Everyone else only sees the
+//! key the server gave you (`PlayerState.key`), which is also how `get_rank()` looks players up.
+//!
+//! Players can dress up a little, too.  Before you join, you can ask for a color with
+//! `ConnectionToServer::set_color()`, pick a skin with `set_skin()`, and give yourself a title with
+//! `set_title()`.  Everyone's skin and title come in their `PlayerState` -- what a skin looks like
+//! is up to your client.
+//!
+//! If your connection drops (or your client freezes for a bit), the server holds on to your player
+//! for `GameSettings.resume_grace_period`.  `ConnectionToServer` notices when the game states stop
+//! coming and reconnects on its own, and you get your same player id back -- right where you were,
+//! with the same health.  `ConnectionToServer` also sends the server a heartbeat every so often (on
+//! its own thread, so a minimized window doesn't count as a dropped connection).
+//!
+//! Being connected isn't the same as playing, though.  If you don't move, turn, attack, block or
+//! dodge for `GameSettings.afk_delay`, you're away from keyboard (AFK) and you'll get a
+//! `PlayerEvent::Afk`.  Depending on `GameSettings.afk_spectate`, you either sit out until you do
+//! something (and get a `PlayerEvent::BackFromAfk`), or get kicked.  Sitting out is like
+//! spectating -- someone else can take your place, and then you'll have to wait for room to come
+//! back.
+//!
+//! If the game is full (or you'd just rather watch), call `ConnectionToServer::spectate()` instead
+//! of `join()`.  Spectators get all the same game states, but don't have a player.  They can
+//! `follow()` a player, and `join()` whenever there's room.
+//!
+//! Players can talk to each other, too.  `ConnectionToServer::send_chat()` sends a message to
+//! everyone, `send_team_chat()` only to your team, and `poll_chat_messages()` gets you the
+//! `ChatMessage`s that came in since you last asked.  The server turns down messages that are
+//! longer than `GameSettings.max_chat_length`, or that come too fast (see
+//! `GameSettings.chat_rate_limit`).
+//!
+//! One server can run several arenas (rooms) at once, each with its own `GameSettings`, game mode
+//! and high scores.  `ConnectionToServer::list_rooms()` tells you what rooms there are, and
+//! `set_room()` picks one before you `join()` (or `spectate()`).  You start out in room `0`.
+//!
+//! Some rooms are match rooms (`RoomInfo.match_size` isn't `0`), which you can't just join.  Get in
+//! line with `ConnectionToServer::queue()` instead, and check `queue_status()` every second or so.
+//! The lobby matches you up with players of about the same rating, and once you see
+//! `QueueStatus::Matched`, you're already in the right room with a ticket to get in -- just
+//! `join()`.  Nobody else can take your place, since the ticket is only good for you, and only
+//! once.
+//!
+//! The server might not be running the classic, endless free-for-all.  It can also run timed
+//! rounds, first-to-N-points rounds, or last-player-standing rounds (no respawning until the round
+//! is over).  `GameSettings.game_mode` tells you which, and `GameState.round` tells you whether the
+//! round is warming up, on, or over, along with the scores for just this round.
+//!
+//! The server can also split players into teams.  Everyone on a team wears the team's color, kills
+//! count towards the team's points in `GameState.teams`, and `PlayerState.team` tells you who is on
+//! which team.  Unless `GameSettings.friendly_fire` is on, you can't hurt your own teammates -- and
+//! if it is on, killing a teammate costs you a point.
+//!
+//! Some game modes are about more than killing.  In king of the hill, you earn points for being
+//! the only living player on a hill.  In capture the flag, teams grab each other's flags and carry
+//! them home (slowly -- flags are heavy).  Drop a flag and it lies there until somebody picks it
+//! up, or it goes home by itself.  The hills and flags are in `GameState.hills` and
+//! `GameState.flags`, and you'll get `PlayerEvent`s when you pick up, drop, return or capture a
+//! flag.
+//!
+//! Every so often, something shows up in the arena for you to pick up: a health potion, a speed
+//! boost, a damage buff, a shield that lets less damage through, or a different weapon (you'll get
+//! a `PlayerEvent::ChangeWeapon` when you switch).  Buffs wear off after a while
+//! (they're in `PlayerState.buffs`, each with a timer).  Everything lying around is in
+//! `GameState.pickups`, and you'll get a `PlayerEvent` when you pick something up or when a buff
+//! wears off.
+//!
+//! The server can also make the grue close in.  With a shrinking safe zone, the safe part of the
+//! arena gets smaller a phase at a time, and anyone caught outside of it takes damage until they
+//! get back in (it pairs nicely with last-player-standing rounds).  `GameState.zone` has where
+//! the zone is now and where it is shrinking to, so you can draw the darkness coming.  The zone
+//! starts over every round (or, in a free-for-all, once it's done shrinking), and players always
+//! spawn inside of it.
 //!
 //! ## Preparation
 //!
@@ -123,11 +237,7 @@
 //!   or to adjust the volume.  Read up on [rodio](https://github.com/tomaka/rodio), the low-level
 //!   library we use to process and play audio.
 //!   - Update the documentation to be clearer, more comprehensive, and have more useful links.
-//!   - Add support for Game Modes (Teams? Capture the flag?).
-//!   - Add multiple weapon types and random weapon drops to pick up.
-//!   - Add the ability to Parry (you can try to attack OR parry - a successful parry causes the
-//!     attacking player to have a much larger delay than usual before the next attack attempt).
-//!   - Add player collision.
+//!   - Add more Game Modes (Bomb defusal? Tag?).
 //!   - [Create an issue](https://github.com/CleanCut/rusty_sword_arena/issues/new) if you
 //!     have ideas you want to discuss, or need help.
 //!   - [Create a Pull Request](https://github.com/CleanCut/rusty_sword_arena/compare) if you would
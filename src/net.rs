@@ -1,6 +1,15 @@
-use crate::game::{GameControlMsg, GameSettings, GameState, PlayerInput};
+use crate::game::{
+    ChatMessage, GameControlMsg, GameSettings, GameState, HighScores, PlayerInput, PlayerInputMsg,
+    QueueStatus, RankedScore, RoomInfo, ScoreboardPage, WeaponKind,
+};
+use crate::gfx::Color;
 
 use bincode::{deserialize, serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread;
 use std::time::{Duration, Instant};
 use zmq;
 
@@ -10,17 +19,102 @@ pub const PLAYER_INPUT_PORT: i32 = 8001;
 pub const GAME_STATE_PORT: i32 = 8002;
 #[doc(hidden)]
 pub const GAME_CONTROL_PORT: i32 = 8003;
+#[doc(hidden)]
+pub const CHAT_PORT: i32 = 8004;
+
+/// Game states are published under a topic for each room, so that clients only get the game
+/// states for the room they're in.
+#[doc(hidden)]
+pub fn room_topic(room: u8) -> Vec<u8> {
+    vec![b'r', room]
+}
+
+/// Chat messages are published under a topic for each room, so that clients only get chat for
+/// their own room.  Team chat is never published -- anyone can subscribe to anything, so players
+/// fetch their team chat from the server instead.
+#[doc(hidden)]
+pub fn chat_topic(room: u8) -> Vec<u8> {
+    vec![b'a', room]
+}
 
 const PLAYER_INPUT_INTERVAL: Duration = Duration::from_millis(15);
 
+// If we haven't heard from the server in this long, the connection is probably broken
+const RECONNECT_DELAY: Duration = Duration::from_millis(2000);
+
+// How often to send heartbeats if the server doesn't tell us otherwise
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
+
+// How often we ask the server for team chat while we're on a team
+const TEAM_CHAT_FETCH_INTERVAL: Duration = Duration::from_millis(250);
+
+// Sends the server heartbeats from its own thread, so the server knows we're still here even if
+// the game loop gets stuck for a while (like when the window is minimized).  Stops when dropped.
+struct Heartbeat {
+    running: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    fn start(context: &zmq::Context, host: &str, msg: PlayerInputMsg, interval: Duration) -> Self {
+        let socket = context.socket(zmq::PUSH).unwrap();
+        socket.set_linger(0).unwrap();
+        socket
+            .connect(&format!("tcp://{}:{}", host, PLAYER_INPUT_PORT))
+            .unwrap();
+        let msg = serialize(&msg).unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        thread::spawn(move || {
+            while thread_running.load(Ordering::SeqCst) {
+                // If the server isn't there, the next heartbeat will have to do
+                let _ = socket.send(&msg, zmq::DONTWAIT);
+                thread::sleep(interval);
+            }
+        });
+        Self { running }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
 /// This is your client's network connection to the server. The methods abstract away all the actual
 /// object serialization and network communication. Hooray for encapsulation!
 pub struct ConnectionToServer {
-    _context: zmq::Context,
+    host: String,
+    context: zmq::Context,
     game_control_socket: zmq::Socket,
     game_state_socket: zmq::Socket,
     player_input_socket: zmq::Socket,
+    chat_socket: zmq::Socket,
+    room: u8,
     last_player_input_sent: Instant,
+    // Whether the last input we sent was dodging, so holding the dodge button only dodges once
+    dodge_sent: bool,
+    last_game_state_received: Instant,
+    player_id: Option<u8>,
+    resume_token: Option<u64>,
+    // The team we're on, if any, so we know whether there's team chat to fetch
+    chat_team: Option<u8>,
+    last_team_chat_fetch: Instant,
+    heartbeat_interval: Duration,
+    heartbeat: Option<Heartbeat>,
+    // The socket we asked the server to give us our player back on, and when we asked.  It gets
+    // its own socket so we can keep going while we wait, without tying up the game control socket.
+    resuming: Option<(zmq::Socket, Instant)>,
+    // Why we couldn't get our player back, until someone asks
+    connection_error: Option<String>,
+    spectator_token: Option<u64>,
+    queue_ticket: Option<u64>,
+    // What gets us into the match room the lobby matched us up in
+    join_ticket: Option<u64>,
+    identity: Option<String>,
+    color: Option<Color>,
+    skin: Option<u16>,
+    title: Option<String>,
 }
 
 impl ConnectionToServer {
@@ -29,70 +123,513 @@ impl ConnectionToServer {
     /// the host.  This neets to be `mut` since it needs to track state internally.
     pub fn new(host: &str) -> Self {
         let context = zmq::Context::new();
+        let (game_control_socket, game_state_socket, player_input_socket, chat_socket) =
+            Self::connect(&context, host, 0);
 
+        Self {
+            host: host.to_string(),
+            context,
+            game_control_socket,
+            game_state_socket,
+            player_input_socket,
+            chat_socket,
+            room: 0,
+            last_player_input_sent: Instant::now(),
+            dodge_sent: false,
+            last_game_state_received: Instant::now(),
+            player_id: None,
+            resume_token: None,
+            chat_team: None,
+            last_team_chat_fetch: Instant::now(),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            heartbeat: None,
+            resuming: None,
+            connection_error: None,
+            spectator_token: None,
+            queue_ticket: None,
+            join_ticket: None,
+            identity: None,
+            color: None,
+            skin: None,
+            title: None,
+        }
+    }
+
+    fn connect(
+        context: &zmq::Context,
+        host: &str,
+        room: u8,
+    ) -> (zmq::Socket, zmq::Socket, zmq::Socket, zmq::Socket) {
+        // Old sockets get dropped whenever we reconnect, and anything they still had waiting to
+        // go out shouldn't keep us hanging around
         let game_control_socket = context.socket(zmq::REQ).unwrap();
+        game_control_socket.set_linger(0).unwrap();
         game_control_socket
             .connect(&format!("tcp://{}:{}", host, GAME_CONTROL_PORT))
             .unwrap();
 
         let game_state_socket = context.socket(zmq::SUB).unwrap();
+        game_state_socket.set_linger(0).unwrap();
         game_state_socket.set_rcvtimeo(0).unwrap();
         game_state_socket
             .connect(&format!("tcp://{}:{}", host, GAME_STATE_PORT))
             .unwrap();
-        game_state_socket.set_subscribe(&[]).unwrap();
+        game_state_socket.set_subscribe(&room_topic(room)).unwrap();
 
         let player_input_socket = context.socket(zmq::PUSH).unwrap();
+        player_input_socket.set_linger(0).unwrap();
         player_input_socket
             .connect(&format!("tcp://{}:{}", host, PLAYER_INPUT_PORT))
             .unwrap();
 
-        Self {
-            _context: context,
+        // Team chat gets subscribed to once we know what team we're on
+        let chat_socket = context.socket(zmq::SUB).unwrap();
+        chat_socket.set_linger(0).unwrap();
+        chat_socket.set_rcvtimeo(0).unwrap();
+        chat_socket
+            .connect(&format!("tcp://{}:{}", host, CHAT_PORT))
+            .unwrap();
+        chat_socket.set_subscribe(&chat_topic(room)).unwrap();
+
+        (
             game_control_socket,
             game_state_socket,
             player_input_socket,
-            last_player_input_sent: Instant::now(),
+            chat_socket,
+        )
+    }
+
+    /// Start over with brand-new connections to the server, and ask the server to give us back
+    /// the player we had before.  If it's been too long since we were connected (see
+    /// `GameSettings.resume_grace_period`), this returns an `Err(String)` and you'll need to
+    /// `.join()` again.  This waits up to a second and a half for the server to answer.  You
+    /// don't usually need to call this yourself -- `.poll_game_states()` does the same thing
+    /// (without the waiting) when it stops hearing from the server.
+    pub fn reconnect(&mut self) -> Result<u8, String> {
+        self.reset_sockets();
+        let token = match self.resume_token {
+            Some(token) => token,
+            None => return Err("Resume Failed: We never joined.".to_string()),
+        };
+        let msg = GameControlMsg::Resume { token };
+        self.game_control_socket.set_rcvtimeo(1500).unwrap();
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let answer = self
+            .game_control_socket
+            .recv_bytes(0)
+            .ok()
+            .map(|bytes| deserialize(&bytes[..]).unwrap());
+        self.game_control_socket.set_rcvtimeo(-1).unwrap();
+        self.resumed(token, answer)
+    }
+
+    /// If we lost the server and couldn't get our player back (see `.poll_game_states()`), this
+    /// returns why, once.  If the server turned us down, you'll need to `.join()` again.
+    pub fn take_connection_error(&mut self) -> Option<String> {
+        self.connection_error.take()
+    }
+
+    fn reset_sockets(&mut self) {
+        let (game_control_socket, game_state_socket, player_input_socket, chat_socket) =
+            Self::connect(&self.context, &self.host, self.room);
+        self.game_control_socket = game_control_socket;
+        self.game_state_socket = game_state_socket;
+        self.player_input_socket = player_input_socket;
+        self.chat_socket = chat_socket;
+        self.chat_team = None;
+        self.last_game_state_received = Instant::now();
+        // Whatever we were waiting to hear back about went out on the old connection
+        self.resuming = None;
+    }
+
+    // Like `.reconnect()`, but only asks -- `.check_resume()` picks up the answer
+    fn start_resume(&mut self, token: u64) {
+        self.reset_sockets();
+        let socket = self.context.socket(zmq::REQ).unwrap();
+        socket.set_linger(0).unwrap();
+        socket
+            .connect(&format!("tcp://{}:{}", self.host, GAME_CONTROL_PORT))
+            .unwrap();
+        let msg = GameControlMsg::Resume { token };
+        match socket.send(&serialize(&msg).unwrap(), zmq::DONTWAIT) {
+            Ok(()) => self.resuming = Some((socket, Instant::now())),
+            Err(err) => self.connection_error = Some(format!("Resume Failed: {}", err)),
         }
     }
 
+    fn check_resume(&mut self) {
+        let bytes = match &self.resuming {
+            None => return,
+            Some((socket, asked)) => match socket.recv_bytes(zmq::DONTWAIT) {
+                Ok(bytes) => Some(bytes),
+                Err(_) if asked.elapsed() < RECONNECT_DELAY => return,
+                Err(_) => None,
+            },
+        };
+        self.resuming = None;
+        let token = match self.resume_token {
+            Some(token) => token,
+            None => return,
+        };
+        // If there's no answer, we'll try again the next time around
+        let _ = self.resumed(token, bytes.map(|bytes| deserialize(&bytes[..]).unwrap()));
+    }
+
+    // `answer` is what the server said about giving us our player back, if it said anything
+    fn resumed(&mut self, token: u64, answer: Option<Result<u8, String>>) -> Result<u8, String> {
+        let result = match answer {
+            Some(Ok(id)) => {
+                self.start_heartbeat(PlayerInputMsg::Heartbeat { id, token });
+                self.connection_error = None;
+                return Ok(id);
+            }
+            Some(Err(err)) => {
+                // If the server said no, asking again won't help
+                self.resume_token = None;
+                self.heartbeat = None;
+                Err(err)
+            }
+            None => Err("Resume Failed: The server didn't answer.".to_string()),
+        };
+        self.connection_error = result.clone().err();
+        result
+    }
+
     /// Join a game.  If successful, this returns an `Ok(u8)` representing your
     /// player id. Save the player id, because it is how you will be able to tell which of the
     /// players the server tells you about later is YOU! If unsuccessful then this returns an
     /// `Err(String)` that you can unwrap and print out to see an informative error message.
     pub fn join(&mut self, name: &str) -> Result<u8, String> {
-        let msg = GameControlMsg::Join {
+        self.send_join(name, None)
+    }
+
+    /// Just like `.join()`, but you get to pick which kind of weapon your player starts with
+    /// instead of the default Rusty Sword.
+    pub fn join_with_weapon(&mut self, name: &str, weapon: WeaponKind) -> Result<u8, String> {
+        self.send_join(name, Some(weapon))
+    }
+
+    /// The server keeps high scores from game to game.  By default they're kept under your name,
+    /// so if you change your name you start over, and anyone using your name gets your points.
+    /// Call this before joining with a string that is unique to you (a random one that you save in
+    /// a file is a good choice) and your scores will follow you no matter what name you use.  Keep
+    /// it secret -- the server never shows it to anyone, and anyone who knows it gets your scores.
+    pub fn set_identity(&mut self, identity: &str) {
+        self.identity = Some(identity.to_string());
+    }
+
+    /// Call this before joining to ask for a color.  Each of `r`, `g`, and `b` goes from `0.0` to
+    /// `1.0`.  The server will give you some other color if yours isn't, if yours is too close to
+    /// someone else's, or if you end up on a team (teammates all wear the team's color).
+    pub fn set_color(&mut self, color: Color) {
+        self.color = Some(color);
+    }
+
+    /// Call this before joining to pick a skin.  Skins are numbered from `0` up to (but not
+    /// including) `GameSettings.skins`, and it's up to each client to decide what they look like.
+    /// If you don't pick one, you get skin `0`.
+    pub fn set_skin(&mut self, skin: u16) {
+        self.skin = Some(skin);
+    }
+
+    /// Call this before joining to give yourself a title, like "the Unready".  It can't be longer
+    /// than `GameSettings.max_title_length` characters.
+    pub fn set_title(&mut self, title: &str) {
+        self.title = Some(title.to_string());
+    }
+
+    /// The server can run several arenas (rooms) at once, each with its own game settings, game
+    /// mode and high scores.  This lists them all.
+    pub fn list_rooms(&mut self) -> Vec<RoomInfo> {
+        let msg = GameControlMsg::ListRooms;
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        let rooms: Vec<RoomInfo> = deserialize(&bytes[..]).unwrap();
+        rooms
+    }
+
+    /// Pick which room to play (or watch) in, before you join or spectate.  You start out in room
+    /// `0`.  Everything else -- game states, game settings, scoreboards and chat -- comes from the
+    /// room you pick.  Returns an `Err(String)` if there's no such room, or if you're still playing
+    /// or spectating in the room you're in.
+    pub fn set_room(&mut self, room: u8) -> Result<(), String> {
+        if self.player_id.is_some() || self.spectator_token.is_some() {
+            return Err("Leave (or stop spectating) before you switch rooms.".to_string());
+        }
+        if !self
+            .list_rooms()
+            .iter()
+            .any(|room_info| room_info.room == room)
+        {
+            return Err(format!("There is no room {}.", room));
+        }
+        self.game_state_socket
+            .set_unsubscribe(&room_topic(self.room))
+            .unwrap();
+        self.game_state_socket
+            .set_subscribe(&room_topic(room))
+            .unwrap();
+        self.chat_socket
+            .set_unsubscribe(&chat_topic(self.room))
+            .unwrap();
+        self.chat_socket.set_subscribe(&chat_topic(room)).unwrap();
+        self.chat_team = None;
+        // Throw away anything that already arrived from the old room
+        while self.game_state_socket.recv_bytes(0).is_ok() {}
+        while self.chat_socket.recv_bytes(0).is_ok() {}
+        self.room = room;
+        Ok(())
+    }
+
+    /// Instead of picking a room yourself, get in line for the lobby to match you up with players
+    /// of about the same skill.  Pass the match room you'd like to play in, or `None` if any of
+    /// them will do (see `RoomInfo.match_size` in `.list_rooms()`).  Then call `.queue_status()`
+    /// every second or so until you get a match -- if you stop asking, the server forgets about
+    /// you.
+    pub fn queue(&mut self, name: &str, room: Option<u8>) -> Result<(), String> {
+        if self.player_id.is_some() {
+            return Err("Queue Failed: Leave the game you're in first.".to_string());
+        }
+        let msg = GameControlMsg::Queue {
             name: name.to_string(),
+            identity: self.identity.clone(),
+            room,
+        };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        let result: Result<u64, String> = deserialize(&bytes[..]).unwrap();
+        self.queue_ticket = Some(result?);
+        Ok(())
+    }
+
+    /// Find out how your wait for a match is going.  Once you're matched, this switches you to the
+    /// match room (see `.set_room()`) and holds on to your join ticket, so all that's left is to
+    /// `.join()`.  Returns an `Err(String)` if you aren't in the queue.
+    pub fn queue_status(&mut self) -> Result<QueueStatus, String> {
+        let ticket = match self.queue_ticket {
+            Some(ticket) => ticket,
+            None => return Err("You aren't in the queue.".to_string()),
         };
+        let msg = GameControlMsg::FetchQueueStatus { ticket };
         self.game_control_socket
             .send(&serialize(&msg).unwrap(), 0)
             .unwrap();
         let bytes = self.game_control_socket.recv_bytes(0).unwrap();
-        let result: Result<u8, String> = deserialize(&bytes[..]).unwrap();
+        let result: Result<QueueStatus, String> = deserialize(&bytes[..]).unwrap();
+        match result {
+            Ok(QueueStatus::Waiting { .. }) => {}
+            Ok(QueueStatus::Matched { room, join_ticket }) => {
+                self.queue_ticket = None;
+                self.join_ticket = Some(join_ticket);
+                self.stop_spectating();
+                self.set_room(room)?;
+            }
+            _ => self.queue_ticket = None,
+        }
         result
     }
 
+    /// Get out of line.  Returns `false` if you weren't in the queue.
+    pub fn leave_queue(&mut self) -> bool {
+        let ticket = match self.queue_ticket.take() {
+            Some(ticket) => ticket,
+            None => return false,
+        };
+        let msg = GameControlMsg::LeaveQueue { ticket };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        deserialize(&bytes[..]).unwrap()
+    }
+
+    fn send_join(&mut self, name: &str, weapon: Option<WeaponKind>) -> Result<u8, String> {
+        let msg = GameControlMsg::Join {
+            room: self.room,
+            name: name.to_string(),
+            weapon,
+            identity: self.identity.clone(),
+            color: self.color,
+            skin: self.skin,
+            title: self.title.clone(),
+            join_ticket: self.join_ticket,
+        };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        let result: Result<(u8, u64), String> = deserialize(&bytes[..]).unwrap();
+        let (id, resume_token) = result?;
+        // Join tickets are only good once
+        self.join_ticket = None;
+        // Now that we're playing, we're not spectating anymore
+        self.stop_spectating();
+        self.player_id = Some(id);
+        self.resume_token = Some(resume_token);
+        self.resuming = None;
+        self.connection_error = None;
+        self.heartbeat_interval = self.fetch_heartbeat_interval();
+        self.start_heartbeat(PlayerInputMsg::Heartbeat {
+            id,
+            token: resume_token,
+        });
+        self.last_game_state_received = Instant::now();
+        Ok(id)
+    }
+
+    // We're already in by the time we ask, so if the settings don't come through the usual
+    // interval will have to do
+    fn fetch_heartbeat_interval(&mut self) -> Duration {
+        self.get_game_settings()
+            .map(|game_settings| Duration::from_millis(game_settings.heartbeat_interval))
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL)
+    }
+
+    fn start_heartbeat(&mut self, msg: PlayerInputMsg) {
+        // Stop the old heartbeat (if any) before starting a new one
+        self.heartbeat = None;
+        self.heartbeat = Some(Heartbeat::start(
+            &self.context,
+            &self.host,
+            msg,
+            self.heartbeat_interval,
+        ));
+    }
+
+    /// Watch the game without playing.  You'll get game states from `.poll_game_states()` just
+    /// like a player does, but you won't have a player of your own (and you don't take up one of
+    /// the `GameSettings.max_players` spots).  Pass the id of a player to `follow` if you want to
+    /// keep an eye on someone in particular.  When you're ready to play, just `.join()` -- that
+    /// stops spectating for you.
+    pub fn spectate(&mut self, name: &str, follow: Option<u8>) -> Result<(), String> {
+        let msg = GameControlMsg::Spectate {
+            room: self.room,
+            name: name.to_string(),
+            follow,
+        };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        let result: Result<u64, String> = deserialize(&bytes[..]).unwrap();
+        let token = result?;
+        self.spectator_token = Some(token);
+        self.heartbeat_interval = self.fetch_heartbeat_interval();
+        self.start_heartbeat(PlayerInputMsg::SpectatorHeartbeat { token });
+        Ok(())
+    }
+
+    /// While spectating, follow the player with this id, or `None` to stop following anyone.
+    /// Everyone can see who spectators are following in `GameState.spectators`.  Returns `false`
+    /// if you aren't spectating or there's no such player.
+    pub fn follow(&mut self, id: Option<u8>) -> bool {
+        let token = match self.spectator_token {
+            Some(token) => token,
+            None => return false,
+        };
+        let msg = GameControlMsg::Follow { token, id };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        deserialize(&bytes[..]).unwrap()
+    }
+
+    /// Stop spectating.  Returns `false` if you weren't spectating.
+    pub fn stop_spectating(&mut self) -> bool {
+        let token = match self.spectator_token.take() {
+            Some(token) => token,
+            None => return false,
+        };
+        self.heartbeat = None;
+        let msg = GameControlMsg::StopSpectating { token };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        deserialize(&bytes[..]).unwrap()
+    }
+
     /// Get the current `GameSettings`.  You should look at the version number and make sure that
-    /// you are connecting to a version of the server you expect.
+    /// you are connecting to a version of the server you expect.  Returns an `Err(String)` if
+    /// the room you're in doesn't exist (maybe the server restarted with fewer rooms).
     // TODO: Do the version check here.
-    pub fn get_game_settings(&mut self) -> GameSettings {
-        let msg = GameControlMsg::Fetch;
+    pub fn get_game_settings(&mut self) -> Result<GameSettings, String> {
+        let msg = GameControlMsg::Fetch { room: self.room };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        deserialize(&bytes[..]).unwrap()
+    }
+
+    /// Get the full scoreboard: every player's score and stats, not just the top 10 that come in
+    /// each `GameState`.  See `HighScores::sorted_by()` if you want to show it sorted by something
+    /// other than points.
+    pub fn get_scoreboard(&mut self) -> HighScores {
+        let msg = GameControlMsg::FetchScoreboard { room: self.room };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        let high_scores: HighScores = deserialize(&bytes[..]).unwrap();
+        high_scores
+    }
+
+    /// Get one page of the scoreboard, `page_size` scores long.  The first page is `0`.  The
+    /// server won't send more than 100 scores in a page, no matter how many you ask for.
+    pub fn get_scoreboard_page(&mut self, page: usize, page_size: usize) -> ScoreboardPage {
+        let msg = GameControlMsg::FetchScoreboardPage {
+            room: self.room,
+            page,
+            page_size,
+        };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        let scoreboard_page: ScoreboardPage = deserialize(&bytes[..]).unwrap();
+        scoreboard_page
+    }
+
+    /// Get the score and rank of the player with this key (see `PlayerState.key` and
+    /// `Score::key()`).  Returns `None` if the server has never heard of them.  Your own rank also
+    /// comes with every `GameState` in `PlayerState.rank`.
+    pub fn get_rank(&mut self, key: u64) -> Option<RankedScore> {
+        let msg = GameControlMsg::FetchRank {
+            room: self.room,
+            key,
+        };
         self.game_control_socket
             .send(&serialize(&msg).unwrap(), 0)
             .unwrap();
         let bytes = self.game_control_socket.recv_bytes(0).unwrap();
-        let game_settings: GameSettings = deserialize(&bytes[..]).unwrap();
-        game_settings
+        let ranked_score: Option<RankedScore> = deserialize(&bytes[..]).unwrap();
+        ranked_score
     }
 
-    /// Cause the selected player id to leave the game.  You should pass in your own player id,
-    /// obviously.  Passing in someone else's player id would be really mean.
+    /// Cause the selected player id to leave the game.  You should pass in your own player id --
+    /// the server won't let you make anyone else leave.  Returns `false` if you weren't playing.
     pub fn leave(&mut self, id: u8) -> bool {
-        let msg = GameControlMsg::Leave { id };
+        let token = match self.resume_token {
+            Some(token) => token,
+            None => return false,
+        };
+        let msg = GameControlMsg::Leave { id, token };
         self.game_control_socket.set_rcvtimeo(1500).unwrap();
         self.game_control_socket
             .send(&serialize(&msg).unwrap(), 0)
             .unwrap();
+        self.player_id = None;
+        self.resume_token = None;
+        self.resuming = None;
+        self.heartbeat = None;
         if let Ok(bytes) = self.game_control_socket.recv_bytes(0) {
             let succeeded: bool = deserialize(&bytes[..]).unwrap();
             return succeeded;
@@ -101,23 +638,115 @@ impl ConnectionToServer {
     }
 
     /// Gets all available unprocessed game states.  Game states arrive in order.  You should call
-    /// this every time around your game loop.
+    /// this every time around your game loop.  If the game states stop coming for a couple of
+    /// seconds, this reconnects to the server for you (see `.reconnect()`) without holding up your
+    /// game loop.  If that doesn't work, `.take_connection_error()` tells you why.
     pub fn poll_game_states(&mut self) -> Vec<GameState> {
         let mut game_states = Vec::<GameState>::new();
-        while let Ok(bytes) = self.game_state_socket.recv_bytes(0) {
-            game_states.push(deserialize(&bytes[..]).unwrap());
+        while let Ok(parts) = self.game_state_socket.recv_multipart(0) {
+            if let Some(bytes) = parts.get(1) {
+                game_states.push(deserialize(&bytes[..]).unwrap());
+            }
         }
+        if let Some(game_state) = game_states.last() {
+            self.last_game_state_received = Instant::now();
+            // Keep track of which team we're on, in case there's team chat to fetch
+            self.chat_team = self
+                .player_id
+                .and_then(|id| game_state.player_states.get(&id))
+                .and_then(|player_state| player_state.team);
+        } else if let Some(token) = self.resume_token {
+            if self.resuming.is_none() && self.last_game_state_received.elapsed() >= RECONNECT_DELAY
+            {
+                self.start_resume(token);
+            }
+        }
+        self.check_resume();
         game_states
     }
 
+    /// Send a chat message to everyone.  The server might turn it down if it's too long (see
+    /// `GameSettings.max_chat_length`) or you've been chatting too much lately (see
+    /// `GameSettings.chat_rate_limit`), in which case you get an `Err(String)` saying why.
+    pub fn send_chat(&mut self, text: &str) -> Result<(), String> {
+        self.send_chat_message(text, false)
+    }
+
+    /// Just like `.send_chat()`, but only your teammates get the message.
+    pub fn send_team_chat(&mut self, text: &str) -> Result<(), String> {
+        self.send_chat_message(text, true)
+    }
+
+    fn send_chat_message(&mut self, text: &str, team_only: bool) -> Result<(), String> {
+        let (id, token) = match (self.player_id, self.resume_token) {
+            (Some(id), Some(token)) => (id, token),
+            _ => return Err("You need to join before you can chat.".to_string()),
+        };
+        let msg = GameControlMsg::Chat {
+            id,
+            token,
+            text: text.to_string(),
+            team_only,
+        };
+        self.game_control_socket
+            .send(&serialize(&msg).unwrap(), 0)
+            .unwrap();
+        let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+        deserialize(&bytes[..]).unwrap()
+    }
+
+    /// Gets all the chat messages that have arrived since the last time you called this, oldest
+    /// first.  Like `.poll_game_states()`, you should call this every time around your game loop.
+    /// Team chat comes straight from the server (a few times a second), so only your team ever
+    /// sees it.
+    pub fn poll_chat_messages(&mut self) -> Vec<ChatMessage> {
+        let mut chat_messages = Vec::<ChatMessage>::new();
+        while let Ok(parts) = self.chat_socket.recv_multipart(0) {
+            if let Some(bytes) = parts.get(1) {
+                chat_messages.push(deserialize(&bytes[..]).unwrap());
+            }
+        }
+        if let (Some(id), Some(token), Some(_)) =
+            (self.player_id, self.resume_token, self.chat_team)
+        {
+            if self.last_team_chat_fetch.elapsed() >= TEAM_CHAT_FETCH_INTERVAL {
+                self.last_team_chat_fetch = Instant::now();
+                let msg = GameControlMsg::FetchTeamChat { id, token };
+                self.game_control_socket
+                    .send(&serialize(&msg).unwrap(), 0)
+                    .unwrap();
+                let bytes = self.game_control_socket.recv_bytes(0).unwrap();
+                let result: Result<Vec<ChatMessage>, String> = deserialize(&bytes[..]).unwrap();
+                if let Ok(team_messages) = result {
+                    chat_messages.extend(team_messages);
+                    chat_messages.sort_by_key(|chat_message| chat_message.timestamp);
+                }
+            }
+        }
+        chat_messages
+    }
+
     /// Send player input to the server. This method only actually sends input to the server if it
     /// has been >= 15ms since the last time it sent input (to avoid overwhelming the server with
     /// too many input packets per client).  Otherwise, it just does nothing.  You should maintain
     /// a mutable PlayerInput in your game loop and try to send it every time around your loop.
     pub fn send_player_input(&mut self, player_input: &PlayerInput) {
+        // The server ignores input from anyone who can't prove they're the player
+        let token = match self.resume_token {
+            Some(token) => token,
+            None => return,
+        };
         if self.last_player_input_sent.elapsed() >= PLAYER_INPUT_INTERVAL {
+            let mut player_input = player_input.clone();
+            let dodge = player_input.dodge;
+            player_input.dodge = dodge && !self.dodge_sent;
+            self.dodge_sent = dodge;
+            let msg = PlayerInputMsg::Input {
+                input: player_input,
+                token,
+            };
             self.player_input_socket
-                .send(&serialize(player_input).unwrap(), 0)
+                .send(&serialize(&msg).unwrap(), 0)
                 .unwrap();
             self.last_player_input_sent = Instant::now();
         }
@@ -81,6 +81,13 @@ impl Timer {
         self.time_left = Duration::from_millis(ms);
     }
 
+    /// Skip straight to the end.  The timer is set to zero and `ready` becomes `true`, just as if
+    /// it had counted all the way down.
+    pub fn finish(&mut self) {
+        self.ready = true;
+        self.time_left = Duration::from_secs(0);
+    }
+
     /// IMPORTANT! You must call this method in your game loop!  This is how the timer counts-down.
     /// Every time you call this, the timer counts down the amount in `delta`.  If the timer reaches
     /// zero, `ready` becomes true and the timer stays at zero until `.reset()` or